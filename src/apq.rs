@@ -0,0 +1,232 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{GraphQLJsonError, GraphQLResponse};
+
+pub const PERSISTED_QUERY_NOT_FOUND: &str = "PersistedQueryNotFound";
+pub const PERSISTED_QUERY_NOT_SUPPORTED: &str = "PersistedQueryNotSupported";
+
+/// The lower case hex SHA-256 of a query document, as used by the APQ protocol
+pub fn hash(query: &str) -> String {
+    Sha256::digest(query.as_bytes()).iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The `extensions` member of a request which refers to a persisted query
+pub fn extensions(hash: &str) -> serde_json::Value {
+    json!({
+        "persistedQuery": {
+            "version": 1,
+            "sha256Hash": hash,
+        }
+    })
+}
+
+fn has_error(response: &GraphQLResponse, message: &str, code: &str) -> bool {
+    let matches = |error: &GraphQLJsonError| {
+        error.message.as_deref() == Some(message) || error.extensions.code.as_deref() == Some(code)
+    };
+
+    response.errors.as_ref().is_some_and(|errors| errors.iter().any(matches))
+}
+
+pub(crate) fn is_not_found(response: &GraphQLResponse) -> bool {
+    has_error(response, PERSISTED_QUERY_NOT_FOUND, "PERSISTED_QUERY_NOT_FOUND")
+}
+
+pub(crate) fn is_not_supported(response: &GraphQLResponse) -> bool {
+    has_error(response, PERSISTED_QUERY_NOT_SUPPORTED, "PERSISTED_QUERY_NOT_SUPPORTED")
+}
+
+/// Automatic persisted query state for a `Client`, the hashes the server is known to have and whether it
+/// turned out not to support the protocol at all.
+#[derive(Debug)]
+pub struct PersistedQueries {
+    known: Mutex<HashSet<String>>,
+    supported: AtomicBool,
+}
+
+impl Default for PersistedQueries {
+    fn default() -> Self {
+        PersistedQueries::new()
+    }
+}
+
+impl PersistedQueries {
+    pub fn new() -> PersistedQueries {
+        PersistedQueries {
+            known: Mutex::new(HashSet::new()),
+            supported: AtomicBool::new(true),
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.supported.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_unsupported(&self) {
+        self.supported.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_known(&self, hash: &str) -> bool {
+        self.known.lock().unwrap().contains(hash)
+    }
+
+    pub(crate) fn remember(&self, hash: &str) {
+        self.known.lock().unwrap().insert(hash.to_string());
+    }
+
+    pub(crate) fn forget(&self, hash: &str) {
+        self.known.lock().unwrap().remove(hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::test_server::{StubResponse, StubServer};
+    use crate::{Client, GraphQLType, InputParams};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct Account {
+        number: String,
+    }
+
+    impl GraphQLType<InputParams<&str>> for Account {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "number".to_string()
+        }
+    }
+
+    fn json_response(body: serde_json::Value) -> StubResponse {
+        StubResponse::new(200, "application/json", &body.to_string())
+    }
+
+    /// A server with an APQ cache which starts empty, returned so a test can evict from it
+    async fn apq_server() -> (StubServer, Arc<Mutex<HashMap<String, String>>>) {
+        let cache = Arc::new(Mutex::new(HashMap::new()));
+        let server_cache = cache.clone();
+
+        let server = StubServer::start(move |request| {
+            let body = request.json();
+            let hash = body["extensions"]["persistedQuery"]["sha256Hash"].as_str().unwrap().to_string();
+            let mut cache = cache.lock().unwrap();
+
+            match body["query"].as_str() {
+                Some(query) => {
+                    assert_eq!(super::hash(query), hash);
+                    cache.insert(hash, query.to_string());
+                },
+                None if !cache.contains_key(&hash) => {
+                    return json_response(json!({"errors": [{"message": PERSISTED_QUERY_NOT_FOUND, "extensions": {"code": "PERSISTED_QUERY_NOT_FOUND"}}]}));
+                },
+                None => {},
+            }
+
+            json_response(json!({"data": {"account": {"number": "A-1"}}}))
+        }).await;
+
+        (server, server_cache)
+    }
+
+    async fn get_account(client: &Client) -> Account {
+        client.new_call("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1"), None).await.unwrap()
+    }
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(hash("{ __typename }"), "7f56e67dd21ab3f30d1ff8b7bed08893f0a0db86449836189b361dd1e56ddb4b");
+    }
+
+    #[tokio::test]
+    async fn test_persisted_query_miss_then_hit() {
+        let (server, cache) = apq_server().await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap()
+            .with_persisted_queries(true)
+            .build().unwrap();
+
+        // An unknown hash is registered by sending the whole document with it
+        assert_eq!(get_account(&client).await.number, "A-1");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].json()["query"].as_str().unwrap().contains("account(accountNumber: $accountNumber)"));
+        assert!(requests[0].json()["extensions"]["persistedQuery"]["sha256Hash"].is_string());
+
+        assert_eq!(get_account(&client).await.number, "A-1");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].json().get("query").is_none());
+        assert_eq!(requests[1].json()["variables"], json!({"accountNumber": "A-1"}));
+
+        // Once the server evicts the hash it is a miss, then the document is sent again
+        cache.lock().unwrap().clear();
+        assert_eq!(get_account(&client).await.number, "A-1");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[2].json().get("query").is_none());
+        assert!(requests[3].json()["query"].is_string());
+        assert!(client.persisted_queries.as_ref().unwrap().is_known(&hash(requests[3].json()["query"].as_str().unwrap())));
+    }
+
+    #[tokio::test]
+    async fn test_persisted_queries_not_supported() {
+        let server = StubServer::start(|request| {
+            if request.json().get("extensions").is_some() {
+                json_response(json!({"errors": [{"message": PERSISTED_QUERY_NOT_SUPPORTED}]}))
+            }
+            else {
+                json_response(json!({"data": {"account": {"number": "A-1"}}}))
+            }
+        }).await;
+
+        let client = Client::builder().with_url(server.url.clone()).unwrap()
+            .with_persisted_queries(true)
+            .build().unwrap();
+
+        get_account(&client).await;
+        get_account(&client).await;
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[2].json().get("extensions").is_none());
+    }
+}
@@ -0,0 +1,542 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use std::collections::HashMap;
+use std::fmt;
+
+use futures::future::BoxFuture;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{Client, Error, GraphQLJsonError, GraphQLType, InputParams, OperationType, ResponseBody};
+
+/// The standard `extensions.code` for a request whose credentials were missing or invalid
+pub const UNAUTHENTICATED: &str = "UNAUTHENTICATED";
+
+/// Kraken error code for an expired JSON Web Token
+pub const KRAKEN_JWT_EXPIRED: &str = "KT-CT-1124";
+
+/// Supplies authentication headers for each request made by a `Client`
+pub trait AuthProvider: Send + Sync {
+    /// Headers to add to the next request
+    fn headers(&self) -> BoxFuture<'_, Result<HashMap<String, String>, Error>>;
+
+    /// Obtain new credentials after a request sent with the `rejected` headers failed with an auth error,
+    /// returns false if that is not possible in which case the request is not retried.
+    ///
+    /// Several requests may fail with the same credentials so implementations should only fetch new ones
+    /// once, if the current headers already differ from `rejected` then another request has refreshed them.
+    fn refresh<'a>(&'a self, rejected: &'a HashMap<String, String>) -> BoxFuture<'a, Result<bool, Error>>;
+
+    /// Whether a GraphQL error means the credentials have expired, an HTTP 401 always does. By default
+    /// this is an `UNAUTHENTICATED` code, permission errors are not included since new credentials would
+    /// not change the result.
+    fn is_auth_error(&self, error: &GraphQLJsonError) -> bool {
+        error.extensions.code.as_deref() == Some(UNAUTHENTICATED)
+    }
+}
+
+/// Whether a request failed because of its credentials and may be sent again once they are refreshed. A
+/// mutation is only sent again if every operation in it failed, otherwise those in a batch which succeeded
+/// would run twice.
+pub(crate) fn is_auth_failure<R: ResponseBody>(auth_provider: &dyn AuthProvider, operation_type: OperationType, result: &Result<R, Error>) -> bool {
+    match result {
+        Err(Error::HttpError(status)) => *status == StatusCode::UNAUTHORIZED,
+        Ok(response) => {
+            let operations = response.operation_errors();
+            let failed = |errors: &Vec<&GraphQLJsonError>| errors.iter().any(|error| auth_provider.is_auth_error(error));
+
+            match operation_type {
+                OperationType::Mutation => !operations.is_empty() && operations.iter().all(failed),
+                _ => operations.iter().any(failed),
+            }
+        },
+        Err(_) => false,
+    }
+}
+
+fn header(name: &str, value: &str) -> HashMap<String, String> {
+    HashMap::from([(name.to_string(), value.to_string())])
+}
+
+/// A fixed token sent in the `Authorization` header, or another header set with `with_header`
+#[derive(Debug)]
+pub struct StaticToken {
+    header: String,
+    value: String,
+}
+
+impl StaticToken {
+    pub fn new(value: String) -> StaticToken {
+        StaticToken {
+            header: "Authorization".to_string(),
+            value,
+        }
+    }
+
+    pub fn with_header(mut self, header: &str) -> StaticToken {
+        self.header = header.to_string();
+        self
+    }
+}
+
+impl AuthProvider for StaticToken {
+    fn headers(&self) -> BoxFuture<'_, Result<HashMap<String, String>, Error>> {
+        Box::pin(async move { Ok(header(&self.header, &self.value)) })
+    }
+
+    fn refresh<'a>(&'a self, _rejected: &'a HashMap<String, String>) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(async move { Ok(false) })
+    }
+}
+
+type TokenSource = Box<dyn Fn() -> BoxFuture<'static, Result<String, Error>> + Send + Sync>;
+
+/// A token obtained from `fetch` when first needed and again whenever a request fails with an auth error
+pub struct RefreshableToken {
+    header: String,
+    token: Mutex<Option<String>>,
+    fetch: TokenSource,
+}
+
+impl fmt::Debug for RefreshableToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefreshableToken")
+            .field("header", &self.header)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RefreshableToken {
+    pub fn new<F>(fetch: F) -> RefreshableToken
+    where F: Fn() -> BoxFuture<'static, Result<String, Error>> + Send + Sync + 'static
+    {
+        RefreshableToken {
+            header: "Authorization".to_string(),
+            token: Mutex::new(None),
+            fetch: Box::new(fetch),
+        }
+    }
+
+    pub fn with_header(mut self, header: &str) -> RefreshableToken {
+        self.header = header.to_string();
+        self
+    }
+}
+
+impl AuthProvider for RefreshableToken {
+    fn headers(&self) -> BoxFuture<'_, Result<HashMap<String, String>, Error>> {
+        Box::pin(async move {
+            let mut token = self.token.lock().await;
+
+            if token.is_none() {
+                *token = Some((self.fetch)().await?);
+            }
+
+            Ok(header(&self.header, token.as_deref().unwrap_or_default()))
+        })
+    }
+
+    fn refresh<'a>(&'a self, rejected: &'a HashMap<String, String>) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(async move {
+            let mut token = self.token.lock().await;
+
+            if token.is_some() && token.as_ref() != rejected.get(&self.header) {
+                return Ok(true);
+            }
+
+            *token = Some((self.fetch)().await?);
+            Ok(true)
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ObtainTokenResult {
+    token: String,
+    refresh_token: Option<String>,
+}
+
+impl GraphQLType<InputParams<serde_json::Value>> for ObtainTokenResult {
+    fn get_query_attributes(_params: &InputParams<serde_json::Value>, _prefix: &str) -> String {
+        "token\n  refreshToken".to_string()
+    }
+}
+
+#[derive(Debug)]
+struct TokenState {
+    token: String,
+    refresh_token: Option<String>,
+}
+
+/// A token obtained by a GraphQL mutation such as Kraken's `obtainKrakenToken`.
+///
+/// The mutation is called with `input` to get the first token, if the result includes a `refreshToken`
+/// then that is used to get subsequent tokens, falling back to `input` if it has also expired.
+/// The mutation is sent by `client`, which should not itself have this provider.
+#[derive(Debug)]
+pub struct GraphQLTokenProvider {
+    client: Client,
+    header: String,
+    mutation_name: String,
+    input_type: String,
+    input: serde_json::Value,
+    auth_error_codes: Vec<String>,
+    state: Mutex<Option<TokenState>>,
+}
+
+impl GraphQLTokenProvider {
+    pub fn new(client: Client, mutation_name: &str, input_type: &str, input: serde_json::Value) -> GraphQLTokenProvider {
+        GraphQLTokenProvider {
+            client,
+            header: "Authorization".to_string(),
+            mutation_name: mutation_name.to_string(),
+            input_type: input_type.to_string(),
+            input,
+            auth_error_codes: Vec::new(),
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Kraken `obtainKrakenToken` authenticated with an API key, refreshing when the token has expired
+    pub fn kraken(client: Client, api_key: &str) -> GraphQLTokenProvider {
+        GraphQLTokenProvider::new(client, "obtainKrakenToken", "ObtainJSONWebTokenInput!", serde_json::json!({ "APIKey": api_key }))
+            .with_auth_error_code(KRAKEN_JWT_EXPIRED)
+    }
+
+    /// An `extensions.errorCode` which also means the token has expired, as well as `UNAUTHENTICATED`
+    pub fn with_auth_error_code(mut self, error_code: &str) -> GraphQLTokenProvider {
+        self.auth_error_codes.push(error_code.to_string());
+        self
+    }
+
+    async fn obtain(&self, input: serde_json::Value) -> Result<TokenState, Error> {
+        let params = InputParams::new("input", &self.input_type, input);
+        let result: ObtainTokenResult = self.client.new_mutation("ObtainToken", &self.mutation_name, params, None).await?;
+
+        Ok(TokenState {
+            token: result.token,
+            refresh_token: result.refresh_token,
+        })
+    }
+
+    async fn renew(&self, state: &mut Option<TokenState>) -> Result<(), Error> {
+        let refresh_token = state.as_ref().and_then(|state| state.refresh_token.clone());
+
+        if let Some(refresh_token) = refresh_token {
+            if let Ok(new_state) = self.obtain(serde_json::json!({ "refreshToken": refresh_token })).await {
+                *state = Some(new_state);
+                return Ok(());
+            }
+        }
+
+        *state = Some(self.obtain(self.input.clone()).await?);
+        Ok(())
+    }
+}
+
+impl AuthProvider for GraphQLTokenProvider {
+    fn headers(&self) -> BoxFuture<'_, Result<HashMap<String, String>, Error>> {
+        Box::pin(async move {
+            let mut state = self.state.lock().await;
+
+            if state.is_none() {
+                self.renew(&mut state).await?;
+            }
+
+            let token = state.as_ref().map(|state| state.token.as_str()).unwrap_or_default();
+            Ok(header(&self.header, token))
+        })
+    }
+
+    fn refresh<'a>(&'a self, rejected: &'a HashMap<String, String>) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(async move {
+            let mut state = self.state.lock().await;
+
+            if let Some(current) = state.as_ref() {
+                if rejected.get(&self.header) != Some(&current.token) {
+                    return Ok(true);
+                }
+            }
+
+            self.renew(&mut state).await?;
+            Ok(true)
+        })
+    }
+
+    fn is_auth_error(&self, error: &GraphQLJsonError) -> bool {
+        error.extensions.code.as_deref() == Some(UNAUTHENTICATED)
+            || error.extensions.error_code.as_ref().is_some_and(|error_code| self.auth_error_codes.contains(error_code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use crate::test_server::{StubResponse, StubServer};
+    use crate::NoParams;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Viewer {
+        id: String,
+    }
+
+    impl GraphQLType<NoParams> for Viewer {
+        fn get_query_attributes(_params: &NoParams, _prefix: &str) -> String {
+            "id".to_string()
+        }
+    }
+
+    fn viewer() -> StubResponse {
+        StubResponse::new(200, "application/json", &json!({"data": {"viewer": {"id": "V-1"}}}).to_string())
+    }
+
+    fn expired() -> StubResponse {
+        StubResponse::new(200, "application/json", &json!({"data": {}, "errors": [{
+            "message": "Signature of the JWT has expired.",
+            "locations": [],
+            "path": ["viewer"],
+            "extensions": {"errorType": "APPLICATION", "errorCode": KRAKEN_JWT_EXPIRED}
+        }]}).to_string())
+    }
+
+    #[tokio::test]
+    async fn test_static_token() {
+        let server = StubServer::start(|_request| StubResponse::new(401, "text/plain", "Unauthorized")).await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap()
+            .with_auth_provider(StaticToken::new("Bearer secret".to_string()))
+            .build().unwrap();
+
+        let result = client.new_call::<Viewer, NoParams>("Viewer", "viewer", NoParams, None).await;
+
+        assert!(matches!(result, Err(Error::HttpError(StatusCode::UNAUTHORIZED))));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].headers["authorization"], "Bearer secret");
+    }
+
+    #[tokio::test]
+    async fn test_refreshable_token() {
+        let server = StubServer::start(|request| {
+            if request.headers.get("authorization").map(String::as_str) == Some("token-2") {
+                viewer()
+            }
+            else {
+                StubResponse::new(401, "text/plain", "Unauthorized")
+            }
+        }).await;
+
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let provider = RefreshableToken::new(move || {
+            let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            Box::pin(async move { Ok(format!("token-{}", count)) })
+        });
+
+        let client = Client::builder().with_url(server.url.clone()).unwrap()
+            .with_auth_provider(provider)
+            .build().unwrap();
+
+        let viewer: Viewer = client.new_call("Viewer", "viewer", NoParams, None).await.unwrap();
+
+        assert_eq!(viewer.id, "V-1");
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        assert_eq!(server.requests().len(), 2);
+
+        // The refreshed token is kept for subsequent calls
+        let _: Viewer = client.new_call("Viewer", "viewer", NoParams, None).await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_refresh() {
+        let server = StubServer::start(|request| {
+            if request.headers.get("authorization").map(String::as_str) == Some("token-2") {
+                viewer()
+            }
+            else {
+                StubResponse::new(401, "text/plain", "Unauthorized")
+            }
+        }).await;
+
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let provider = RefreshableToken::new(move || {
+            let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            Box::pin(async move { Ok(format!("token-{}", count)) })
+        });
+
+        let client = Client::builder().with_url(server.url.clone()).unwrap()
+            .with_auth_provider(provider)
+            .build().unwrap();
+
+        let calls = (0..5).map(|_| client.new_call::<Viewer, NoParams>("Viewer", "viewer", NoParams, None));
+        let viewers = futures::future::join_all(calls).await;
+
+        assert!(viewers.into_iter().all(|viewer| viewer.unwrap().id == "V-1"));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_permission_error_not_refreshed() {
+        let server = StubServer::start(|_request| {
+            StubResponse::new(200, "application/json", &json!({"data": {}, "errors": [{
+                "message": "Not authorized",
+                "locations": [],
+                "path": ["viewer"],
+                "extensions": {"errorType": "AUTHORIZATION"}
+            }]}).to_string())
+        }).await;
+
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let provider = RefreshableToken::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok("token".to_string()) })
+        });
+
+        let client = Client::builder().with_url(server.url.clone()).unwrap()
+            .with_auth_provider(provider)
+            .build().unwrap();
+
+        let result = client.new_call::<Viewer, NoParams>("Viewer", "viewer", NoParams, None).await;
+
+        assert!(matches!(result, Err(Error::GraphQLError(_))));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unauthenticated_refreshed() {
+        let server = StubServer::start(|request| {
+            if request.headers.get("authorization").map(String::as_str) == Some("token-2") {
+                viewer()
+            }
+            else {
+                StubResponse::new(200, "application/json", &json!({"data": {"viewer": null}, "errors": [{
+                    "message": "Not authenticated",
+                    "path": ["viewer"],
+                    "extensions": {"code": UNAUTHENTICATED}
+                }]}).to_string())
+            }
+        }).await;
+
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let provider = RefreshableToken::new(move || {
+            let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            Box::pin(async move { Ok(format!("token-{}", count)) })
+        });
+
+        let client = Client::builder().with_url(server.url.clone()).unwrap()
+            .with_auth_provider(provider)
+            .build().unwrap();
+
+        let viewer: Viewer = client.new_call("Viewer", "viewer", NoParams, None).await.unwrap();
+
+        assert_eq!(viewer.id, "V-1");
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    /// Hands out a new token every time it is asked, recording the headers it is told were rejected
+    struct RotatingToken {
+        calls: AtomicUsize,
+        rejected: Arc<std::sync::Mutex<Vec<HashMap<String, String>>>>,
+    }
+
+    impl AuthProvider for RotatingToken {
+        fn headers(&self) -> BoxFuture<'_, Result<HashMap<String, String>, Error>> {
+            let count = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            Box::pin(async move { Ok(header("Authorization", &format!("token-{}", count))) })
+        }
+
+        fn refresh<'a>(&'a self, rejected: &'a HashMap<String, String>) -> BoxFuture<'a, Result<bool, Error>> {
+            self.rejected.lock().unwrap().push(rejected.clone());
+            Box::pin(async move { Ok(true) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh_gets_sent_headers() {
+        let server = StubServer::start(|_request| StubResponse::new(401, "text/plain", "Unauthorized")).await;
+
+        let rejected = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let client = Client::builder().with_url(server.url.clone()).unwrap()
+            .with_auth_provider(RotatingToken { calls: AtomicUsize::new(0), rejected: rejected.clone() })
+            .build().unwrap();
+
+        let result = client.new_call::<Viewer, NoParams>("Viewer", "viewer", NoParams, None).await;
+        assert!(matches!(result, Err(Error::HttpError(StatusCode::UNAUTHORIZED))));
+
+        let requests = server.requests();
+        let rejected = rejected.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0]["Authorization"], requests[0].headers["authorization"]);
+    }
+
+    #[tokio::test]
+    async fn test_kraken_token() {
+        let server = StubServer::start(|request| {
+            let body = request.json();
+
+            if body["query"].as_str().unwrap().contains("obtainKrakenToken") {
+                let input = &body["variables"]["input"];
+                let result = match input["refreshToken"].as_str() {
+                    Some("refresh-1") => json!({"token": "jwt-2", "refreshToken": "refresh-2"}),
+                    _ => {
+                        assert_eq!(input["APIKey"], "sk_live_key");
+                        json!({"token": "jwt-1", "refreshToken": "refresh-1"})
+                    }
+                };
+                return StubResponse::new(200, "application/json", &json!({"data": {"obtainKrakenToken": result}}).to_string());
+            }
+
+            match request.headers.get("authorization").map(String::as_str) {
+                Some("jwt-2") => viewer(),
+                _ => expired(),
+            }
+        }).await;
+
+        let client = Client::builder().with_url(server.url.clone()).unwrap()
+            .with_auth_provider(GraphQLTokenProvider::kraken(Client::new(server.url.clone()), "sk_live_key"))
+            .build().unwrap();
+
+        let viewer: Viewer = client.new_call("Viewer", "viewer", NoParams, None).await.unwrap();
+
+        assert_eq!(viewer.id, "V-1");
+
+        let authorizations: Vec<Option<String>> = server.requests().iter().map(|request| request.headers.get("authorization").cloned()).collect();
+        assert_eq!(authorizations, vec![None, Some("jwt-1".to_string()), None, Some("jwt-2".to_string())]);
+    }
+}
@@ -0,0 +1,337 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+
+use crate::{Client, Error, ErrorPolicy, GraphQLQueryParams, GraphQLResponse, GraphQLType, OperationType, PartialResponse, Request};
+
+struct BatchOperation {
+    operation_type: OperationType,
+    request_name: String,
+    query: String,
+    variables: HashMap<String, serde_json::Value>,
+}
+
+/// Identifies one operation in a `Batch` and the type its result decodes to
+#[derive(Debug)]
+pub struct BatchHandle<T> {
+    index: usize,
+    query_name: String,
+    result_type: PhantomData<fn() -> T>,
+}
+
+/// Collects operations to send together as a JSON array in one HTTP request, for servers which support
+/// batching. Each `query` or `mutation` returns a handle used to take its result from the `BatchResults`.
+pub struct Batch<'a> {
+    client: &'a Client,
+    operations: Vec<BatchOperation>,
+}
+
+impl<'a> Batch<'a> {
+    pub(crate) fn new(client: &'a Client) -> Batch<'a> {
+        Batch {
+            client,
+            operations: Vec::new(),
+        }
+    }
+
+    pub fn query<T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams>(&mut self, request_name: &str, query_name: &str, params: Q) -> Result<BatchHandle<T>, Error> {
+        self.add(OperationType::Query, request_name, query_name, &params)
+    }
+
+    pub fn mutation<T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams>(&mut self, request_name: &str, query_name: &str, params: Q) -> Result<BatchHandle<T>, Error> {
+        self.add(OperationType::Mutation, request_name, query_name, &params)
+    }
+
+    fn add<T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams>(&mut self, operation_type: OperationType, request_name: &str, query_name: &str, params: &Q) -> Result<BatchHandle<T>, Error> {
+        self.operations.push(BatchOperation {
+            operation_type,
+            request_name: request_name.to_string(),
+            query: T::get_document(operation_type, request_name, query_name, params),
+            variables: params.get_variable_map()?,
+        });
+
+        Ok(BatchHandle {
+            index: self.operations.len() - 1,
+            query_name: query_name.to_string(),
+            result_type: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Send every operation in one request. This fails only if the request as a whole does, errors for
+    /// individual operations are returned when their results are taken.
+    pub async fn send<'h>(self, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<BatchResults, Error> {
+        let payload: Vec<Request<&HashMap<String, serde_json::Value>>> = self.operations.iter()
+            .map(|operation| Request {
+                query: &operation.query,
+                variables: &operation.variables,
+                operation_name: &operation.request_name,
+                extensions: None,
+            })
+            .collect();
+
+        let operation_type = match self.operations.iter().any(|operation| operation.operation_type == OperationType::Mutation) {
+            true => OperationType::Mutation,
+            false => OperationType::Query,
+        };
+        let operation_names: Vec<&str> = self.operations.iter()
+            .map(|operation| operation.request_name.as_str())
+            .collect();
+
+        let responses: Vec<GraphQLResponse> = self.client.post_batch(operation_type, &operation_names, serde_json::to_string(&payload)?, headers).await?;
+
+        if responses.len() != self.operations.len() {
+            return Err(Error::InternalError(format!("Sent a batch of {} operations but received {} responses", self.operations.len(), responses.len())));
+        }
+
+        Ok(BatchResults {
+            responses: responses.into_iter().map(Some).collect(),
+            error_policy: self.client.error_policy,
+        })
+    }
+}
+
+/// The responses to a `Batch`, in the order the operations were added. Errors are handled according to the
+/// client's error policy, as for `new_call`.
+#[derive(Debug)]
+pub struct BatchResults {
+    responses: Vec<Option<GraphQLResponse>>,
+    error_policy: ErrorPolicy,
+}
+
+impl BatchResults {
+    /// Decode the result of one operation, each result can be taken once
+    pub fn take<T: DeserializeOwned>(&mut self, handle: &BatchHandle<T>) -> Result<T, Error> {
+        self.take_partial(handle)?.into_result()
+    }
+
+    /// As `take` but returns any partial data with the errors, as allowed by the error policy
+    pub fn take_partial<T: DeserializeOwned>(&mut self, handle: &BatchHandle<T>) -> Result<PartialResponse<T>, Error> {
+        self.responses.get_mut(handle.index)
+            .and_then(Option::take)
+            .ok_or_else(|| Error::InternalError(format!("No result for batch operation {}", handle.index)))?
+            .into_partial(&handle.query_name)
+            .with_policy(self.error_policy)?
+            .deserialize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use reqwest::StatusCode;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::test_server::{StubResponse, StubServer};
+    use crate::{InputParams, MockTransport, RefreshableToken, TransportResponse};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct Account {
+        number: String,
+    }
+
+    impl GraphQLType<InputParams<&str>> for Account {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "number".to_string()
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct Viewer {
+        id: String,
+    }
+
+    impl GraphQLType<InputParams<&str>> for Viewer {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "id".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        let server = StubServer::start(|request| {
+            let responses: Vec<serde_json::Value> = request.json().as_array().unwrap().iter()
+                .map(|operation| match operation["variables"]["accountNumber"].as_str() {
+                    Some("A-1") => json!({"data": {"account": {"number": "A-1"}}}),
+                    Some(_) => json!({"data": {"account": null}, "errors": [{"message": "Not found", "path": ["account"]}]}),
+                    None => json!({"data": {"viewer": {"id": "V-1"}}}),
+                })
+                .collect();
+
+            StubResponse::new(200, "application/json", &serde_json::Value::Array(responses).to_string())
+        }).await;
+
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let mut batch = client.batch();
+        let found = batch.query::<Account, _>("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1")).unwrap();
+        let missing = batch.query::<Account, _>("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-2")).unwrap();
+        let viewer = batch.query::<Viewer, _>("GetViewer", "viewer", InputParams::new("id", "ID", "")).unwrap();
+        assert_eq!(batch.len(), 3);
+
+        let mut results = batch.send(None).await.unwrap();
+
+        assert_eq!(results.take(&viewer).unwrap().id, "V-1");
+        assert_eq!(results.take(&found).unwrap().number, "A-1");
+        assert!(matches!(results.take(&missing), Err(Error::GraphQLError(_))));
+        assert!(matches!(results.take(&found), Err(Error::InternalError(_))));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].json()[2]["operationName"], "GetViewer");
+    }
+
+    #[tokio::test]
+    async fn test_batch_mock_transport() {
+        let transport = Arc::new(MockTransport::new());
+        transport
+            .respond("GetAccount", json!({"account": {"number": "A-1"}}))
+            .respond("GetViewer", json!({"viewer": {"id": "V-1"}}));
+
+        let client = Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(transport.clone())
+            .build().unwrap();
+
+        let mut batch = client.batch();
+        let account = batch.query::<Account, _>("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1")).unwrap();
+        let viewer = batch.query::<Viewer, _>("GetViewer", "viewer", InputParams::new("id", "ID", "")).unwrap();
+
+        let mut results = batch.send(None).await.unwrap();
+
+        assert_eq!(results.take(&account).unwrap().number, "A-1");
+        assert_eq!(results.take(&viewer).unwrap().id, "V-1");
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].operation_name, "GetAccount");
+        assert_eq!(requests[0].variables, json!({"accountNumber": "A-1"}));
+        assert_eq!(requests[1].operation_name, "GetViewer");
+    }
+
+    #[tokio::test]
+    async fn test_batch_error_policy() {
+        let partial = json!({"data": {"account": {"number": "A-1"}}, "errors": [{"message": "Balance unavailable", "path": ["account", "balance"]}]});
+
+        let transport = Arc::new(MockTransport::new());
+        transport.respond_with("GetAccount", TransportResponse::new(StatusCode::OK, partial.to_string()));
+
+        for (error_policy, error_count) in [(ErrorPolicy::Fail, None), (ErrorPolicy::Ignore, Some(0)), (ErrorPolicy::All, Some(1))] {
+            let client = Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+                .with_transport(transport.clone())
+                .with_error_policy(error_policy)
+                .build().unwrap();
+
+            let mut batch = client.batch();
+            let first = batch.query::<Account, _>("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1")).unwrap();
+            let second = batch.query::<Account, _>("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1")).unwrap();
+
+            let mut results = batch.send(None).await.unwrap();
+
+            match error_count {
+                None => {
+                    assert!(matches!(results.take(&first), Err(Error::GraphQLError(_))));
+                    assert!(matches!(results.take_partial(&second), Err(Error::GraphQLError(_))));
+                },
+                Some(error_count) => {
+                    assert_eq!(results.take(&first).unwrap().number, "A-1");
+
+                    let response = results.take_partial(&second).unwrap();
+                    assert_eq!(response.data.unwrap().number, "A-1");
+                    assert_eq!(response.errors.len(), error_count);
+                },
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_mutation_not_resent() {
+        let expired = json!({"data": {"viewer": null}, "errors": [{
+            "message": "Signature of the JWT has expired.",
+            "locations": [],
+            "path": ["viewer"],
+            "extensions": {"code": crate::auth::UNAUTHENTICATED}
+        }]});
+
+        let transport = Arc::new(MockTransport::new());
+        transport
+            .respond("UpdateAccount", json!({"account": {"number": "A-1"}}))
+            .respond_with("GetViewer", TransportResponse::new(StatusCode::OK, expired.to_string()));
+
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let provider = RefreshableToken::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok("token".to_string()) })
+        });
+
+        let client = Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(transport.clone())
+            .with_auth_provider(provider)
+            .build().unwrap();
+
+        let mut batch = client.batch();
+        let account = batch.mutation::<Account, _>("UpdateAccount", "account", InputParams::new("accountNumber", "String!", "A-1")).unwrap();
+        let viewer = batch.query::<Viewer, _>("GetViewer", "viewer", InputParams::new("id", "ID", "")).unwrap();
+
+        let mut results = batch.send(None).await.unwrap();
+
+        // The mutation succeeded so the batch is not sent again, which would repeat it
+        assert_eq!(results.take(&account).unwrap().number, "A-1");
+        assert!(matches!(results.take(&viewer), Err(Error::GraphQLError(_))));
+        assert_eq!(transport.requests().len(), 2);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_batch_length_mismatch() {
+        let server = StubServer::start(|_request| {
+            StubResponse::new(200, "application/json", "[]")
+        }).await;
+
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let mut batch = client.batch();
+        batch.query::<Account, _>("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1")).unwrap();
+
+        assert!(matches!(batch.send(None).await, Err(Error::InternalError(_))));
+    }
+}
@@ -0,0 +1,164 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+//! A synchronous client for programs which do not otherwise need an async runtime. It wraps the async
+//! `Client`, so requests are built and responses decoded in exactly the same way, and drives it on a
+//! private single threaded runtime. Its methods must not be called from within an async context.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{ClientBuilder, Error, GraphQLQueryParams, GraphQLType, PartialResponse, ResponseEnvelope};
+
+pub struct Client {
+    inner: crate::Client,
+    runtime: Runtime,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("blocking::Client")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
+    pub fn new(url: String) -> Result<Client, Error> {
+        ClientBuilder::new().with_url(url)?.build_blocking()
+    }
+
+    pub(crate) fn from_async(inner: crate::Client) -> Result<Client, Error> {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|err| Error::InternalError(format!("Failed to start runtime: {}", err)))?;
+
+        Ok(Client {
+            inner,
+            runtime,
+        })
+    }
+
+    /// The async client which does the work
+    pub fn inner(&self) -> &crate::Client {
+        &self.inner
+    }
+
+    pub fn new_call<'h, T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams>(&self, request_name: &str, query_name: &str, params: Q, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<T, Error> {
+        self.runtime.block_on(self.inner.new_call(request_name, query_name, params, headers))
+    }
+
+    pub fn new_call_partial<'h, T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams>(&self, request_name: &str, query_name: &str, params: Q, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<PartialResponse<T>, Error> {
+        self.runtime.block_on(self.inner.new_call_partial(request_name, query_name, params, headers))
+    }
+
+    pub fn new_mutation<'h, T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams>(&self, request_name: &str, query_name: &str, params: Q, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<T, Error> {
+        self.runtime.block_on(self.inner.new_mutation(request_name, query_name, params, headers))
+    }
+
+    pub fn new_call_all<'h, T, Q, I>(&self, request_name: &str, query_name: &str, params: I, concurrency: usize, headers: Option<&'h HashMap<&'h str, &String>>) -> Vec<Result<T, Error>>
+    where T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams, I: IntoIterator<Item = Q>
+    {
+        self.runtime.block_on(self.inner.new_call_all(request_name, query_name, params, concurrency, headers))
+    }
+
+    pub fn new_call_envelope<'h, T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams>(&self, request_name: &str, query_name: &str, params: Q, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<ResponseEnvelope<T>, Error> {
+        self.runtime.block_on(self.inner.new_call_envelope(request_name, query_name, params, headers))
+    }
+
+    pub fn call<'h, T>(&self, operation_name: &str, query: &str, variables: &T, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<HashMap<String, serde_json::Value>, Error>
+    where T: Serialize
+    {
+        self.runtime.block_on(self.inner.call(operation_name, query, variables, headers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use crate::test_server::{StubResponse, StubServer};
+    use crate::InputParams;
+
+    #[derive(Deserialize, Debug)]
+    struct Account {
+        number: String,
+    }
+
+    impl GraphQLType<InputParams<&str>> for Account {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "number".to_string()
+        }
+    }
+
+    /// Run the stub server on its own thread, since the test itself has no runtime
+    fn start_server() -> StubServer {
+        let (sender, receiver) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+            runtime.block_on(async move {
+                let server = StubServer::start(|request| {
+                    let body = match request.json()["variables"]["accountNumber"].as_str() {
+                        Some("A-1") => json!({"data": {"account": {"number": "A-1"}}}),
+                        _ => json!({"data": {"account": null}, "errors": [{"message": "Not found", "path": ["account"]}]}),
+                    };
+                    StubResponse::new(200, "application/json", &body.to_string())
+                }).await;
+                sender.send(server).unwrap();
+                std::future::pending::<()>().await;
+            });
+        });
+
+        receiver.recv().unwrap()
+    }
+
+    #[test]
+    fn test_blocking_client() {
+        let server = start_server();
+        let client = Client::new(server.url.clone()).unwrap();
+
+        let account: Account = client.new_call("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1"), None).unwrap();
+        assert_eq!(account.number, "A-1");
+
+        let missing = client.new_call::<Account, _>("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-2"), None);
+        assert!(matches!(missing, Err(Error::GraphQLError(_))));
+
+        assert_eq!(server.requests().len(), 2);
+    }
+}
@@ -0,0 +1,447 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::cassette::normalize_query;
+use crate::{Error, GraphQLQueryParams};
+
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+const DEFAULT_CAPACITY: usize = 1000;
+
+/// The cache key for a query sent by the caller identified by `identity` headers, whitespace in the query
+/// and the order of the variables and headers don't matter. The headers are hashed so no secrets are written
+/// to the disk store.
+pub fn cache_key(query: &str, variables: &HashMap<String, serde_json::Value>, identity: &[(String, String)]) -> String {
+    let identity: BTreeMap<String, &String> = identity.iter().map(|(key, value)| (key.to_lowercase(), value)).collect();
+    let identity: String = Sha256::digest(serde_json::to_string(&identity).unwrap_or_default().as_bytes()).iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    format!("{}\n{}\n{}", normalize_query(query), canonical_variables(variables), identity)
+}
+
+fn canonical_variables(variables: &HashMap<String, serde_json::Value>) -> String {
+    let variables: BTreeMap<&String, &serde_json::Value> = variables.iter().collect();
+
+    serde_json::to_string(&variables).unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    operation_name: String,
+    #[serde(default)]
+    variables: String,
+    value: serde_json::Value,
+    expires_at: SystemTime,
+    #[serde(skip)]
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    clock: u64,
+    dirty: bool,
+}
+
+/// Caches the results of queries made with `new_call` for a time to live which can be set per operation.
+///
+/// Entries are held in memory up to a capacity, beyond which the least recently used are evicted, and can
+/// also be written to a file with `flush` so they survive restarts, which happens anyway when the cache is
+/// dropped. Mutations are never cached. Entries are keyed by the auth provider's headers and any headers
+/// named with `with_identity_header` as well as the query, so callers with different credentials don't see
+/// each other's results while headers which change on every request, such as trace ids, are ignored.
+#[derive(Debug)]
+pub struct ResponseCache {
+    ttl: Duration,
+    operation_ttls: HashMap<String, Duration>,
+    identity_headers: HashSet<String>,
+    capacity: usize,
+    disk_store: Option<PathBuf>,
+    state: Mutex<CacheState>,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache::new()
+    }
+}
+
+impl ResponseCache {
+    pub fn new() -> ResponseCache {
+        ResponseCache {
+            ttl: DEFAULT_TTL,
+            operation_ttls: HashMap::new(),
+            identity_headers: HashSet::new(),
+            capacity: DEFAULT_CAPACITY,
+            disk_store: None,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// The time to live for operations without their own, by default one minute
+    pub fn with_ttl(mut self, ttl: Duration) -> ResponseCache {
+        self.ttl = ttl;
+        self
+    }
+
+    /// The time to live for `operation_name`, zero means it is not cached
+    pub fn with_operation_ttl(mut self, operation_name: &str, ttl: Duration) -> ResponseCache {
+        self.operation_ttls.insert(operation_name.to_string(), ttl);
+        self
+    }
+
+    /// A default or per call header which identifies the caller, such as `Authorization` when credentials
+    /// are passed per call rather than by an auth provider
+    pub fn with_identity_header(mut self, name: &str) -> ResponseCache {
+        self.identity_headers.insert(name.to_lowercase());
+        self
+    }
+
+    pub(crate) fn is_identity_header(&self, name: &str) -> bool {
+        self.identity_headers.contains(&name.to_lowercase())
+    }
+
+    /// The maximum number of entries held, by default 1000
+    pub fn with_capacity(mut self, capacity: usize) -> ResponseCache {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Persist entries to `path` on `flush` and drop, loading any unexpired entries already there
+    pub fn with_disk_store(mut self, path: impl Into<PathBuf>) -> Result<ResponseCache, Error> {
+        let path = path.into();
+
+        if path.exists() {
+            let entries: HashMap<String, CacheEntry> = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+            let now = SystemTime::now();
+
+            self.state.get_mut().unwrap().entries = entries.into_iter()
+                .filter(|(_, entry)| entry.expires_at > now)
+                .collect();
+        }
+
+        self.disk_store = Some(path);
+        Ok(self)
+    }
+
+    fn ttl(&self, operation_name: &str) -> Duration {
+        self.operation_ttls.get(operation_name).copied().unwrap_or(self.ttl)
+    }
+
+    pub fn get(&self, key: &str) -> Option<serde_json::Value> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+
+        match state.entries.get_mut(key) {
+            Some(entry) if entry.expires_at > SystemTime::now() => {
+                entry.last_used = clock;
+                Some(entry.value.clone())
+            },
+            Some(_) => {
+                state.entries.remove(key);
+                None
+            },
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, operation_name: &str, key: String, variables: &HashMap<String, serde_json::Value>, value: serde_json::Value) {
+        let ttl = self.ttl(operation_name);
+
+        if ttl.is_zero() || self.capacity == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+
+        let entry = CacheEntry {
+            operation_name: operation_name.to_string(),
+            variables: canonical_variables(variables),
+            value,
+            expires_at: SystemTime::now() + ttl,
+            last_used: state.clock,
+        };
+        state.entries.insert(key, entry);
+
+        while state.entries.len() > self.capacity {
+            let oldest = state.entries.iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+            }
+        }
+
+        state.dirty = true;
+    }
+
+    /// Remove the entries for `operation_name` called with `params`, for every caller
+    pub fn invalidate<Q: GraphQLQueryParams>(&self, operation_name: &str, params: &Q) -> Result<(), Error> {
+        let variables = canonical_variables(&params.get_variable_map()?);
+
+        let mut state = self.state.lock().unwrap();
+        state.entries.retain(|_, entry| entry.operation_name != operation_name || entry.variables != variables);
+        state.dirty = true;
+
+        Ok(())
+    }
+
+    /// Remove every entry for `operation_name`, typically after a mutation which changes its results
+    pub fn invalidate_operation(&self, operation_name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.retain(|_, entry| entry.operation_name != operation_name);
+        state.dirty = true;
+    }
+
+    pub fn invalidate_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.dirty = true;
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write the entries to the disk store if there is one and they have changed since the last flush
+    pub fn flush(&self) -> Result<(), Error> {
+        let Some(path) = &self.disk_store else {
+            return Ok(());
+        };
+
+        let json = {
+            let mut state = self.state.lock().unwrap();
+
+            if !state.dirty {
+                return Ok(());
+            }
+
+            state.dirty = false;
+            serde_json::to_string(&state.entries)?
+        };
+
+        if let Err(error) = std::fs::write(path, json) {
+            self.state.lock().unwrap().dirty = true;
+            return Err(error.into());
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for ResponseCache {
+    fn drop(&mut self) {
+        if let Err(error) = self.flush() {
+            tracing::warn!(%error, "failed to write response cache");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use crate::{Client, GraphQLType, InputParams, MockTransport};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct Account {
+        number: String,
+        balance: i32,
+    }
+
+    impl GraphQLType<InputParams<&str>> for Account {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "number\n  balance".to_string()
+        }
+    }
+
+    fn cached_client(transport: &Arc<MockTransport>, cache: ResponseCache) -> Client {
+        Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(transport.clone())
+            .with_cache(cache)
+            .build().unwrap()
+    }
+
+    fn account_transport() -> Arc<MockTransport> {
+        let transport = Arc::new(MockTransport::new());
+        transport
+            .respond("GetAccount", json!({"account": {"number": "A-1", "balance": 100}}))
+            .respond("GetAccount", json!({"account": {"number": "A-1", "balance": 50}}))
+            .respond("UpdateAccount", json!({"account": {"number": "A-1", "balance": 0}}));
+        transport
+    }
+
+    async fn get_account(client: &Client, account_number: &'static str) -> Account {
+        client.new_call("GetAccount", "account", InputParams::new("account", "String!", account_number), None).await.unwrap()
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let a = HashMap::from([("a".to_string(), json!(1)), ("b".to_string(), json!(2))]);
+        let b = HashMap::from([("b".to_string(), json!(2)), ("a".to_string(), json!(1))]);
+
+        assert_eq!(cache_key("query A {\n  a\n}", &a, &[]), cache_key("query A { a }", &b, &[]));
+        assert_ne!(cache_key("query A { a }", &a, &[]), cache_key("query A { a }", &HashMap::new(), &[]));
+
+        let alice = [("Authorization".to_string(), "alice".to_string())];
+        let bob = [("authorization".to_string(), "bob".to_string())];
+        assert_ne!(cache_key("query A { a }", &a, &alice), cache_key("query A { a }", &a, &bob));
+        assert!(!cache_key("query A { a }", &a, &alice).contains("alice"));
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit_and_invalidate() {
+        let transport = account_transport();
+        let client = cached_client(&transport, ResponseCache::new());
+
+        assert_eq!(get_account(&client, "A-1").await.balance, 100);
+        assert_eq!(get_account(&client, "A-1").await.balance, 100);
+        assert_eq!(transport.requests().len(), 1);
+
+        // Different variables are a different entry
+        get_account(&client, "A-2").await;
+        assert_eq!(transport.requests().len(), 2);
+
+        // Mutations always go to the server
+        let _: Account = client.new_mutation("UpdateAccount", "account", InputParams::new("account", "String!", "A-1"), None).await.unwrap();
+        let _: Account = client.new_mutation("UpdateAccount", "account", InputParams::new("account", "String!", "A-1"), None).await.unwrap();
+        assert_eq!(transport.requests().len(), 4);
+
+        client.cache().unwrap().invalidate_operation("GetAccount");
+        assert_eq!(get_account(&client, "A-1").await.balance, 50);
+        assert_eq!(transport.requests().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_cache_per_identity() {
+        let transport = account_transport();
+        let client = cached_client(&transport, ResponseCache::new().with_identity_header("Authorization"));
+
+        for (index, token) in ["alice", "bob", "alice"].into_iter().enumerate() {
+            let token = token.to_string();
+            let request_id = index.to_string();
+            let headers = HashMap::from([("Authorization", &token), ("X-Request-Id", &request_id)]);
+            let _: Account = client.new_call("GetAccount", "account", InputParams::new("account", "String!", "A-1"), Some(&headers)).await.unwrap();
+        }
+
+        assert_eq!(transport.requests().len(), 2);
+
+        // Both callers' entries go
+        client.cache().unwrap().invalidate("GetAccount", &InputParams::new("account", "String!", "A-1")).unwrap();
+        assert!(client.cache().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cache_auth_provider_identity() {
+        let transport = account_transport();
+        let client = Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(transport.clone())
+            .with_cache(ResponseCache::new())
+            .with_auth_provider(crate::StaticToken::new("alice".to_string()))
+            .build().unwrap();
+
+        get_account(&client, "A-1").await;
+        get_account(&client, "A-2").await;
+        assert_eq!(transport.requests().len(), 2);
+
+        let cache = client.cache().unwrap();
+        cache.invalidate("GetAccount", &InputParams::new("account", "String!", "A-2")).unwrap();
+        get_account(&client, "A-1").await;
+        assert_eq!(transport.requests().len(), 2);
+        get_account(&client, "A-2").await;
+        assert_eq!(transport.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_cache_ttl() {
+        let transport = account_transport();
+        let client = cached_client(&transport, ResponseCache::new().with_operation_ttl("GetAccount", Duration::ZERO));
+
+        get_account(&client, "A-1").await;
+        get_account(&client, "A-1").await;
+
+        assert_eq!(transport.requests().len(), 2);
+        assert!(client.cache().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cache_lru() {
+        let transport = account_transport();
+        let client = cached_client(&transport, ResponseCache::new().with_capacity(2));
+
+        get_account(&client, "A-1").await;
+        get_account(&client, "A-2").await;
+        get_account(&client, "A-1").await;
+        get_account(&client, "A-3").await;
+        assert_eq!(transport.requests().len(), 3);
+
+        // A-2 was least recently used so was evicted by A-3
+        get_account(&client, "A-1").await;
+        assert_eq!(transport.requests().len(), 3);
+        get_account(&client, "A-2").await;
+        assert_eq!(transport.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_cache_disk_store() {
+        let path = std::env::temp_dir().join(format!("sparko_graphql_cache_{}.json", std::process::id()));
+
+        let transport = account_transport();
+        let client = cached_client(&transport, ResponseCache::new().with_disk_store(&path).unwrap());
+        get_account(&client, "A-1").await;
+        assert!(!path.exists());
+
+        client.cache().unwrap().flush().unwrap();
+        assert!(path.exists());
+        drop(client);
+
+        let transport = account_transport();
+        let client = cached_client(&transport, ResponseCache::new().with_disk_store(&path).unwrap());
+        assert_eq!(get_account(&client, "A-1").await.balance, 100);
+        assert!(transport.requests().is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,386 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::transport::RequestBody;
+use crate::{Error, Redaction, Transport, TransportRequest, TransportResponse};
+
+/// Whether a `Client` records its traffic to a cassette file or replays it from one
+#[derive(Debug, Clone)]
+pub enum CassetteMode {
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CassetteRequest {
+    pub operation_name: String,
+    pub query: String,
+    pub variables: serde_json::Value,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CassetteResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+/// One request and the response it received
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Interaction {
+    pub request: CassetteRequest,
+    pub response: CassetteResponse,
+}
+
+/// Collapse runs of whitespace so formatting changes to a query don't change the recording
+pub fn normalize_query(query: &str) -> String {
+    query.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The normalized query and scrubbed variables which identify a request. A batch gives its queries one per
+/// line and an array of the variables of each operation.
+fn identify(request: &TransportRequest, redaction: &Redaction) -> Result<(String, serde_json::Value), Error> {
+    match request.payload()? {
+        serde_json::Value::Array(operations) => {
+            let bodies = operations.into_iter().map(serde_json::from_value).collect::<Result<Vec<RequestBody>, _>>()?;
+            let query = bodies.iter().map(|body| normalize_query(&body.query)).collect::<Vec<_>>().join("\n");
+            let variables = bodies.iter().map(|body| redaction.json(&body.variables)).collect();
+
+            Ok((query, serde_json::Value::Array(variables)))
+        },
+        payload => {
+            let body: RequestBody = serde_json::from_value(payload)?;
+            Ok((normalize_query(&body.query), redaction.json(&body.variables)))
+        },
+    }
+}
+
+pub fn load(path: &Path) -> Result<Vec<Interaction>, Error> {
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+/// Forwards requests to another transport and writes every interaction to a cassette file, with headers
+/// and variables scrubbed according to `Redaction`. Response bodies are stored as received so replay
+/// returns the same data.
+///
+/// The file is rewritten after each response so it is complete even if the process does not exit cleanly.
+/// A failure to record is logged and does not affect the response.
+pub struct RecordingTransport {
+    inner: Arc<dyn Transport>,
+    path: PathBuf,
+    redaction: Redaction,
+    interactions: Mutex<Vec<Interaction>>,
+}
+
+impl RecordingTransport {
+    pub fn new<T: Transport + 'static>(inner: T, path: impl Into<PathBuf>) -> RecordingTransport {
+        RecordingTransport {
+            inner: Arc::new(inner),
+            path: path.into(),
+            redaction: Redaction::default(),
+            interactions: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn with_redaction(mut self, redaction: Redaction) -> RecordingTransport {
+        self.redaction = redaction;
+        self
+    }
+
+    fn record(&self, request: &TransportRequest, response: &TransportResponse) -> Result<(), Error> {
+        let (query, variables) = identify(request, &self.redaction)?;
+
+        let interaction = Interaction {
+            request: CassetteRequest {
+                operation_name: request.operation_name.clone(),
+                query,
+                variables,
+                headers: request.headers.iter()
+                    .map(|(key, value)| (key.clone(), self.redaction.header_value(key, value).to_string()))
+                    .collect(),
+            },
+            response: CassetteResponse {
+                status: response.status.as_u16(),
+                body: response.body.clone(),
+            },
+        };
+
+        let mut interactions = self.interactions.lock().unwrap();
+        interactions.push(interaction);
+        std::fs::write(&self.path, serde_json::to_string_pretty(&*interactions)?)?;
+
+        Ok(())
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn send(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportResponse, Error>> {
+        Box::pin(async move {
+            let response = self.inner.send(request.clone()).await?;
+
+            if let Err(error) = self.record(&request, &response) {
+                tracing::warn!(operation = %request.operation_name, path = %self.path.display(), %error, "failed to record interaction");
+            }
+
+            Ok(response)
+        })
+    }
+}
+
+/// Answers requests from a cassette file without touching the network.
+///
+/// Requests are matched on operation name and variables, after the same scrubbing applied when recording.
+/// Repeated requests get the recorded responses in order, the last one repeating once they are used up.
+/// A request which was never recorded fails with `CassetteError`.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    interactions: Mutex<Vec<(Interaction, bool)>>,
+    redaction: Redaction,
+}
+
+impl ReplayTransport {
+    pub fn new(interactions: Vec<Interaction>) -> ReplayTransport {
+        ReplayTransport {
+            interactions: Mutex::new(interactions.into_iter().map(|interaction| (interaction, false)).collect()),
+            redaction: Redaction::default(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<ReplayTransport, Error> {
+        Ok(ReplayTransport::new(load(path.as_ref())?))
+    }
+
+    /// Must match the redaction used when the cassette was recorded
+    pub fn with_redaction(mut self, redaction: Redaction) -> ReplayTransport {
+        self.redaction = redaction;
+        self
+    }
+
+    fn replay(&self, request: &TransportRequest) -> Result<TransportResponse, Error> {
+        let (_, variables) = identify(request, &self.redaction)?;
+
+        let mut interactions = self.interactions.lock().unwrap();
+        let matches = |interaction: &Interaction| {
+            interaction.request.operation_name == request.operation_name && interaction.request.variables == variables
+        };
+
+        let index = interactions.iter().position(|(interaction, used)| !used && matches(interaction))
+            .or_else(|| interactions.iter().rposition(|(interaction, _)| matches(interaction)))
+            .ok_or_else(|| Error::CassetteError(format!("No recorded response for operation {} with variables {}", request.operation_name, variables)))?;
+
+        let (interaction, used) = &mut interactions[index];
+        *used = true;
+
+        let status = StatusCode::from_u16(interaction.response.status)
+            .map_err(|err| Error::CassetteError(err.to_string()))?;
+
+        Ok(TransportResponse::new(status, interaction.response.body.clone()))
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportResponse, Error>> {
+        Box::pin(async move {
+            self.replay(&request)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::{Client, GraphQLType, InputParams, MockTransport};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct Account {
+        number: String,
+        balance: i32,
+    }
+
+    impl GraphQLType<InputParams<&str>> for Account {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "number\n  balance".to_string()
+        }
+    }
+
+    fn cassette_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("sparko_graphql_{}_{}.json", name, std::process::id()))
+    }
+
+    fn client(mode: CassetteMode, transport: MockTransport) -> Client {
+        Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(transport)
+            .with_cassette(mode)
+            .build().unwrap()
+    }
+
+    async fn get_account(client: &Client, account_number: &'static str) -> Result<Account, Error> {
+        let token = "jwt".to_string();
+        let headers = std::collections::HashMap::from([("Authorization", &token)]);
+
+        client.new_call("GetAccount", "account", InputParams::new("account", "String!", account_number), Some(&headers)).await
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = cassette_path("record_and_replay");
+
+        let transport = MockTransport::new();
+        transport.respond("GetAccount", json!({"account": {"number": "A-1", "balance": 100}, "token": "jwt"}));
+
+        let recorder = client(CassetteMode::Record(path.clone()), transport);
+        assert_eq!(get_account(&recorder, "A-1").await.unwrap().balance, 100);
+
+        let interactions = load(&path).unwrap();
+        assert_eq!(interactions.len(), 1);
+        assert_eq!(interactions[0].request.operation_name, "GetAccount");
+        assert_eq!(interactions[0].request.query, normalize_query(&interactions[0].request.query));
+        assert!(interactions[0].request.query.contains("account(account: $account) { #get_query_part number balance }"));
+        assert!(interactions[0].request.headers.contains(&("Authorization".to_string(), crate::redact::REDACTED.to_string())));
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&interactions[0].response.body).unwrap()["data"]["token"], "jwt");
+
+        // Replay with a transport which has nothing scripted, so any response must come from the cassette
+        let player = client(CassetteMode::Replay(path.clone()), MockTransport::new());
+        assert_eq!(get_account(&player, "A-1").await.unwrap().balance, 100);
+        assert_eq!(get_account(&player, "A-1").await.unwrap().balance, 100);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay_batch() {
+        let path = cassette_path("record_and_replay_batch");
+
+        let transport = MockTransport::new();
+        transport
+            .respond("GetAccount", json!({"account": {"number": "A-1", "balance": 100}}))
+            .respond("GetOtherAccount", json!({"account": {"number": "A-2", "balance": 5}}));
+
+        let batch_balances = |client: Client| async move {
+            let mut batch = client.batch();
+            let first = batch.query::<Account, _>("GetAccount", "account", InputParams::new("account", "String!", "A-1")).unwrap();
+            let second = batch.query::<Account, _>("GetOtherAccount", "account", InputParams::new("account", "String!", "A-2")).unwrap();
+            let mut results = batch.send(None).await.unwrap();
+
+            (results.take(&first).unwrap().balance, results.take(&second).unwrap().balance)
+        };
+
+        assert_eq!(batch_balances(client(CassetteMode::Record(path.clone()), transport)).await, (100, 5));
+
+        let interactions = load(&path).unwrap();
+        assert_eq!(interactions.len(), 1);
+        assert_eq!(interactions[0].request.operation_name, "GetAccount,GetOtherAccount");
+        assert_eq!(interactions[0].request.variables, json!([{"account": "A-1"}, {"account": "A-2"}]));
+
+        assert_eq!(batch_balances(client(CassetteMode::Replay(path.clone()), MockTransport::new())).await, (100, 5));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_record_failure_returns_response() {
+        let path = std::env::temp_dir().join("sparko_graphql_missing_directory").join("cassette.json");
+
+        let transport = MockTransport::new();
+        transport.respond("GetAccount", json!({"account": {"number": "A-1", "balance": 100}}));
+
+        let recorder = client(CassetteMode::Record(path.clone()), transport);
+
+        assert_eq!(get_account(&recorder, "A-1").await.unwrap().balance, 100);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_replay_scrubbed_variables() {
+        let path = cassette_path("replay_scrubbed");
+
+        let transport = MockTransport::new();
+        transport.respond("GetAccount", json!({"account": {"number": "A-1", "balance": 100}}));
+
+        let redaction = Redaction::none().with_field("account");
+        let recorder = Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(transport)
+            .with_redaction(redaction.clone())
+            .with_cassette(CassetteMode::Record(path.clone()))
+            .build().unwrap();
+        get_account(&recorder, "A-1").await.unwrap();
+
+        assert_eq!(load(&path).unwrap()[0].request.variables, json!({"account": crate::redact::REDACTED}));
+
+        let player = ReplayTransport::load(&path).unwrap().with_redaction(redaction);
+        let request = TransportRequest {
+            method: reqwest::Method::POST,
+            url: "http://localhost/graphql".to_string(),
+            operation_name: "GetAccount".to_string(),
+            headers: Vec::new(),
+            content_type: Some("application/json".to_string()),
+            body: json!({"query": "query GetAccount", "variables": {"account": "A-2"}}).to_string().into_bytes(),
+        };
+        assert_eq!(player.send(request).await.unwrap().status, StatusCode::OK);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_unmatched() {
+        let player = ReplayTransport::new(vec![Interaction {
+            request: CassetteRequest {
+                operation_name: "GetAccount".to_string(),
+                query: "query GetAccount".to_string(),
+                variables: json!({"account": "A-1"}),
+                headers: Vec::new(),
+            },
+            response: CassetteResponse {
+                status: 200,
+                body: json!({"data": {"account": {"number": "A-1", "balance": 100}}}).to_string(),
+            },
+        }]);
+
+        let client = Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(player)
+            .build().unwrap();
+
+        assert_eq!(get_account(&client, "A-1").await.unwrap().number, "A-1");
+
+        match get_account(&client, "A-2").await {
+            Err(Error::CassetteError(message)) => assert!(message.contains("GetAccount")),
+            other => panic!("Expected CassetteError but got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,727 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::{Map, Value};
+
+use crate::OperationType;
+
+const ROOT_QUERY: &str = "ROOT_QUERY";
+const REF: &str = "__ref";
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+/// A field in a selection set
+#[derive(Debug, PartialEq)]
+pub(crate) struct Selection {
+    alias: Option<String>,
+    name: String,
+    arguments: Option<String>,
+    selections: Option<Vec<Selection>>,
+}
+
+impl Selection {
+    fn response_key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+
+    /// The key a field is stored under, fields with arguments are stored separately for each set of values
+    fn storage_key(&self, variables: &HashMap<String, Value>) -> String {
+        match &self.arguments {
+            Some(arguments) => format!("{}({})", self.name, resolve_arguments(arguments, variables)),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// The position after the string literal which starts at `start`, either `"..."` or a `"""..."""` block string
+fn string_end(chars: &[char], start: usize) -> usize {
+    const BLOCK_QUOTE: [char; 3] = ['"', '"', '"'];
+
+    if chars[start..].starts_with(&BLOCK_QUOTE) {
+        let mut position = start + 3;
+
+        while position < chars.len() {
+            if chars[position] == '\\' && chars[position + 1..].starts_with(&BLOCK_QUOTE) {
+                position += 4;
+            }
+            else if chars[position..].starts_with(&BLOCK_QUOTE) {
+                return position + 3;
+            }
+            else {
+                position += 1;
+            }
+        }
+    }
+    else {
+        let mut position = start + 1;
+
+        while position < chars.len() {
+            match chars[position] {
+                '\\' => position += 2,
+                '"' => return position + 1,
+                _ => position += 1,
+            }
+        }
+    }
+
+    chars.len()
+}
+
+/// Replace variable references with their values and normalize the whitespace, string literals are kept as written
+fn resolve_arguments(arguments: &str, variables: &HashMap<String, Value>) -> String {
+    let chars: Vec<char> = arguments.chars().collect();
+    let mut resolved = String::new();
+    let mut position = 0;
+    let mut space = false;
+
+    while position < chars.len() {
+        let c = chars[position];
+
+        if c.is_whitespace() {
+            space = true;
+            position += 1;
+            continue;
+        }
+
+        if space && !resolved.is_empty() {
+            resolved.push(' ');
+        }
+        space = false;
+
+        match c {
+            '"' => {
+                let end = string_end(&chars, position);
+                resolved.extend(&chars[position..end]);
+                position = end;
+            },
+            '$' => {
+                let start = position + 1;
+                position = start;
+
+                while chars.get(position).is_some_and(|c| c.is_alphanumeric() || *c == '_') {
+                    position += 1;
+                }
+
+                let name: String = chars[start..position].iter().collect();
+                resolved.push_str(&variables.get(&name).unwrap_or(&Value::Null).to_string());
+            },
+            _ => {
+                resolved.push(c);
+                position += 1;
+            },
+        }
+    }
+
+    resolved
+}
+
+/// Remove comments, a `#` inside a string literal does not start one
+fn strip_comments(document: &str) -> Vec<char> {
+    let chars: Vec<char> = document.chars().collect();
+    let mut text = Vec::with_capacity(chars.len());
+    let mut position = 0;
+
+    while position < chars.len() {
+        match chars[position] {
+            '"' => {
+                let end = string_end(&chars, position);
+                text.extend(&chars[position..end]);
+                position = end;
+            },
+            '#' => {
+                while chars.get(position).is_some_and(|c| *c != '\n') {
+                    position += 1;
+                }
+            },
+            c => {
+                text.push(c);
+                position += 1;
+            },
+        }
+    }
+
+    text
+}
+
+/// Parse the selection set of the first operation in `document`. Returns `None` for anything which
+/// can't be cached field by field, such as fragments and directives.
+pub(crate) fn parse_selections(document: &str) -> Option<Vec<Selection>> {
+    let mut parser = Parser { chars: strip_comments(document), position: 0 };
+
+    // Skip the operation type, name and variable definitions
+    let mut depth = 0;
+    while let Some(c) = parser.peek() {
+        match c {
+            '"' => {
+                parser.position = string_end(&parser.chars, parser.position);
+                continue;
+            },
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            '{' if depth == 0 => break,
+            _ => {},
+        }
+        parser.position += 1;
+    }
+
+    parser.selection_set()
+}
+
+/// The type of the operation called `operation_name` in `document`, or of the first operation if none has
+/// that name. Comments and fragment definitions are skipped and a bare selection set is a query.
+pub(crate) fn operation_type(document: &str, operation_name: &str) -> OperationType {
+    let mut parser = Parser { chars: strip_comments(document), position: 0 };
+    let mut first = None;
+
+    loop {
+        parser.skip_ignored();
+        if parser.peek().is_none() {
+            break;
+        }
+
+        let keyword = parser.name();
+        parser.skip_ignored();
+        let name = parser.name();
+
+        let operation_type = match keyword.as_deref() {
+            Some("fragment") => None,
+            Some("mutation") => Some(OperationType::Mutation),
+            Some("subscription") => Some(OperationType::Subscription),
+            _ => Some(OperationType::Query),
+        };
+
+        if let Some(operation_type) = operation_type {
+            if name.as_deref() == Some(operation_name) {
+                return operation_type;
+            }
+            first.get_or_insert(operation_type);
+        }
+
+        if !parser.skip_definition() {
+            break;
+        }
+    }
+
+    first.unwrap_or(OperationType::Query)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn skip_ignored(&mut self) {
+        while self.peek().is_some_and(|c| c.is_whitespace() || c == ',') {
+            self.position += 1;
+        }
+    }
+
+    /// Move past the selection set which ends the current definition, false if the document ends first
+    fn skip_definition(&mut self) -> bool {
+        let mut parens = 0;
+        let mut braces = 0;
+
+        while let Some(c) = self.peek() {
+            if c == '"' {
+                self.position = string_end(&self.chars, self.position);
+                continue;
+            }
+
+            self.position += 1;
+
+            match c {
+                '(' => parens += 1,
+                ')' => parens -= 1,
+                '{' if parens == 0 => braces += 1,
+                '}' if parens == 0 => {
+                    braces -= 1;
+                    if braces == 0 {
+                        return true;
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        false
+    }
+
+    fn name(&mut self) -> Option<String> {
+        let start = self.position;
+
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            self.position += 1;
+        }
+
+        if start == self.position {
+            None
+        }
+        else {
+            Some(self.chars[start..self.position].iter().collect())
+        }
+    }
+
+    fn arguments(&mut self) -> Option<String> {
+        let start = self.position + 1;
+        let mut depth = 0;
+
+        while let Some(c) = self.peek() {
+            if c == '"' {
+                self.position = string_end(&self.chars, self.position);
+                continue;
+            }
+
+            self.position += 1;
+
+            match c {
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(self.chars[start..self.position - 1].iter().collect());
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        None
+    }
+
+    fn selection_set(&mut self) -> Option<Vec<Selection>> {
+        self.skip_ignored();
+        if self.peek()? != '{' {
+            return None;
+        }
+        self.position += 1;
+
+        let mut selections = Vec::new();
+
+        loop {
+            self.skip_ignored();
+
+            if self.peek()? == '}' {
+                self.position += 1;
+                return Some(selections);
+            }
+
+            let mut name = self.name()?;
+            let mut alias = None;
+
+            self.skip_ignored();
+            if self.peek() == Some(':') {
+                self.position += 1;
+                self.skip_ignored();
+                alias = Some(name);
+                name = self.name()?;
+                self.skip_ignored();
+            }
+
+            let arguments = if self.peek() == Some('(') {
+                let arguments = self.arguments()?;
+                self.skip_ignored();
+                Some(arguments)
+            }
+            else {
+                None
+            };
+
+            let selection_set = if self.peek() == Some('{') {
+                Some(self.selection_set()?)
+            }
+            else {
+                None
+            };
+
+            selections.push(Selection {
+                alias,
+                name,
+                arguments,
+                selections: selection_set,
+            });
+        }
+    }
+}
+
+/// The key for objects which carry `__typename` and `id`
+fn entity_key(object: &Map<String, Value>) -> Option<String> {
+    let typename = object.get("__typename")?.as_str()?;
+
+    match object.get("id")? {
+        Value::String(id) => Some(format!("{}:{}", typename, id)),
+        Value::Number(id) => Some(format!("{}:{}", typename, id)),
+        _ => None,
+    }
+}
+
+/// The stored fields of an entity, or of the root query, and when each of them expires
+#[derive(Debug, Default)]
+struct Record {
+    fields: Map<String, Value>,
+    expires_at: HashMap<String, Instant>,
+}
+
+impl Record {
+    fn merge(&mut self, fields: Map<String, Value>, expires_at: Instant) {
+        for key in fields.keys() {
+            self.expires_at.insert(key.clone(), expires_at);
+        }
+        self.fields.extend(fields);
+    }
+
+    fn get(&self, key: &str, now: Instant) -> Option<&Value> {
+        match self.expires_at.get(key) {
+            Some(expires_at) if *expires_at > now => self.fields.get(key),
+            _ => None,
+        }
+    }
+}
+
+/// An Apollo style normalized cache. Objects with `__typename` and `id` are stored once, keyed by both,
+/// and referenced from wherever they appear, so fetching an entity again updates every query which
+/// includes it.
+///
+/// Queries are answered from the cache when every selected field is present and was stored within the
+/// time to live. Entries are not keyed by credentials, so use a separate cache for each identity.
+#[derive(Debug)]
+pub struct EntityCache {
+    ttl: Duration,
+    entities: Mutex<HashMap<String, Record>>,
+}
+
+impl Default for EntityCache {
+    fn default() -> Self {
+        EntityCache::new()
+    }
+}
+
+impl EntityCache {
+    pub fn new() -> EntityCache {
+        EntityCache {
+            ttl: DEFAULT_TTL,
+            entities: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long a field answers queries after it was last stored, by default one minute
+    pub fn with_ttl(mut self, ttl: Duration) -> EntityCache {
+        self.ttl = ttl;
+        self
+    }
+
+    /// The stored fields of an entity, references to other entities appear as `{"__ref": "Type:id"}`
+    pub fn entity(&self, typename: &str, id: &str) -> Option<Value> {
+        self.entities.lock().unwrap()
+            .get(&format!("{}:{}", typename, id))
+            .map(|record| Value::Object(record.fields.clone()))
+    }
+
+    pub fn evict(&self, typename: &str, id: &str) {
+        self.entities.lock().unwrap().remove(&format!("{}:{}", typename, id));
+    }
+
+    pub fn clear(&self) {
+        self.entities.lock().unwrap().clear();
+    }
+
+    /// Store the result of root field `query_name` for `document`. The entities in a mutation result are
+    /// stored but the mutation itself is not.
+    pub fn write(&self, operation_type: OperationType, document: &str, variables: &HashMap<String, Value>, query_name: &str, value: &Value) {
+        let Some(selections) = parse_selections(document) else {
+            return;
+        };
+
+        let mut data = Map::new();
+        data.insert(query_name.to_string(), value.clone());
+
+        let expires_at = Instant::now() + self.ttl;
+        let mut entities = self.entities.lock().unwrap();
+        let root = normalize_object(&selections, &data, variables, &mut entities, expires_at);
+
+        if operation_type == OperationType::Query {
+            entities.entry(ROOT_QUERY.to_string()).or_default().merge(root, expires_at);
+        }
+    }
+
+    /// The result of root field `query_name` for `document`, if every field it selects is cached
+    pub fn read(&self, document: &str, variables: &HashMap<String, Value>, query_name: &str) -> Option<Value> {
+        let selections = parse_selections(document)?;
+        let entities = self.entities.lock().unwrap();
+
+        let root = Stored::Record(entities.get(ROOT_QUERY)?);
+        let mut data = denormalize_object(&selections, root, variables, &entities, Instant::now())?;
+        data.remove(query_name)
+    }
+}
+
+/// Fields read back from the cache, either a record whose fields may have expired or an object nested in a field
+#[derive(Clone, Copy)]
+enum Stored<'a> {
+    Record(&'a Record),
+    Nested(&'a Map<String, Value>),
+}
+
+impl<'a> Stored<'a> {
+    fn get(self, key: &str, now: Instant) -> Option<&'a Value> {
+        match self {
+            Stored::Record(record) => record.get(key, now),
+            Stored::Nested(object) => object.get(key),
+        }
+    }
+}
+
+fn normalize_object(selections: &[Selection], object: &Map<String, Value>, variables: &HashMap<String, Value>, entities: &mut HashMap<String, Record>, expires_at: Instant) -> Map<String, Value> {
+    let mut stored = Map::new();
+
+    for selection in selections {
+        if let Some(value) = object.get(selection.response_key()) {
+            stored.insert(selection.storage_key(variables), normalize_value(selection.selections.as_deref(), value, variables, entities, expires_at));
+        }
+    }
+
+    stored
+}
+
+fn normalize_value(selections: Option<&[Selection]>, value: &Value, variables: &HashMap<String, Value>, entities: &mut HashMap<String, Record>, expires_at: Instant) -> Value {
+    match (selections, value) {
+        (Some(selections), Value::Object(object)) => {
+            let stored = normalize_object(selections, object, variables, entities, expires_at);
+
+            match entity_key(object) {
+                Some(key) => {
+                    entities.entry(key.clone()).or_default().merge(stored, expires_at);
+
+                    let mut reference = Map::new();
+                    reference.insert(REF.to_string(), Value::String(key));
+                    Value::Object(reference)
+                },
+                None => Value::Object(stored),
+            }
+        },
+        (Some(_), Value::Array(list)) => Value::Array(list.iter().map(|value| normalize_value(selections, value, variables, entities, expires_at)).collect()),
+        (_, value) => value.clone(),
+    }
+}
+
+fn denormalize_object(selections: &[Selection], stored: Stored, variables: &HashMap<String, Value>, entities: &HashMap<String, Record>, now: Instant) -> Option<Map<String, Value>> {
+    let mut object = Map::new();
+
+    for selection in selections {
+        let value = stored.get(&selection.storage_key(variables), now)?;
+        object.insert(selection.response_key().to_string(), denormalize_value(selection.selections.as_deref(), value, variables, entities, now)?);
+    }
+
+    Some(object)
+}
+
+fn denormalize_value(selections: Option<&[Selection]>, value: &Value, variables: &HashMap<String, Value>, entities: &HashMap<String, Record>, now: Instant) -> Option<Value> {
+    match (selections, value) {
+        (Some(selections), Value::Object(object)) => {
+            let stored = match object.get(REF).and_then(Value::as_str) {
+                Some(key) => Stored::Record(entities.get(key)?),
+                None => Stored::Nested(object),
+            };
+
+            Some(Value::Object(denormalize_object(selections, stored, variables, entities, now)?))
+        },
+        (Some(_), Value::Array(list)) => list.iter().map(|value| denormalize_value(selections, value, variables, entities, now)).collect::<Option<Vec<_>>>().map(Value::Array),
+        (_, value) => Some(value.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::{Client, GraphQLType, InputParams, MockTransport};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct Account {
+        id: String,
+        number: String,
+        balance: i32,
+    }
+
+    impl GraphQLType<InputParams<&str>> for Account {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "__typename\n  id\n  number\n  balance".to_string()
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct AccountStatus {
+        id: String,
+        status: String,
+    }
+
+    impl GraphQLType<InputParams<&str>> for AccountStatus {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "__typename\n  id\n  status".to_string()
+        }
+    }
+
+    fn params(account_number: &str) -> InputParams<&str> {
+        InputParams::new("accountNumber", "String!", account_number)
+    }
+
+    #[test]
+    fn test_parse_selections() {
+        let selections = parse_selections(r#"
+            query Account($account_accountNumber: String!, $account_bills_first: Int) {
+                account(accountNumber: $account_accountNumber) { #get_query_part
+                    number
+                    latest: bills(first: $account_bills_first, filter: {kind: "(a)"}) {
+                        id
+                    }
+                } #/get_query_part
+            }
+        "#).unwrap();
+
+        let variables = HashMap::from([
+            ("account_accountNumber".to_string(), json!("A-1")),
+            ("account_bills_first".to_string(), json!(2)),
+        ]);
+
+        assert_eq!(selections.len(), 1);
+        assert_eq!(selections[0].storage_key(&variables), r#"account(accountNumber: "A-1")"#);
+
+        let account = selections[0].selections.as_ref().unwrap();
+        assert_eq!(account[0].name, "number");
+        assert_eq!(account[1].response_key(), "latest");
+        assert_eq!(account[1].storage_key(&variables), r#"bills(first: 2, filter: {kind: "(a)"})"#);
+        assert_eq!(account[1].selections.as_ref().unwrap()[0].name, "id");
+
+        assert!(parse_selections("query A { account { ...AccountFields } }").is_none());
+    }
+
+    #[test]
+    fn test_parse_string_arguments() {
+        let selections = parse_selections(r##"
+            query Search($term: String = "{") {
+                search(text: "# not a comment $term", note: """a "quoted" ) block""", term: $term) { # a comment
+                    id
+                }
+            }
+        "##).unwrap();
+
+        let variables = HashMap::from([("term".to_string(), json!("gas"))]);
+
+        assert_eq!(selections[0].storage_key(&variables), r##"search(text: "# not a comment $term", note: """a "quoted" ) block""", term: "gas")"##);
+        assert_eq!(selections[0].selections.as_ref().unwrap()[0].name, "id");
+
+        let selections = parse_selections(r#"query A { account(name: "a  \"b\"  c") { id } }"#).unwrap();
+        assert_eq!(selections[0].storage_key(&HashMap::new()), r#"account(name: "a  \"b\"  c")"#);
+    }
+
+    #[test]
+    fn test_operation_type() {
+        assert_eq!(operation_type("mutation Rename { viewer { id } }", "Rename"), OperationType::Mutation);
+        assert_eq!(operation_type("{ viewer { id } }", "Viewer"), OperationType::Query);
+        assert_eq!(operation_type("# query comment\n  mutation Rename { viewer { id } }", "Rename"), OperationType::Mutation);
+        assert_eq!(operation_type(r#"
+            fragment Fields on Viewer @include(if: true) { id name(format: "mutation {") }
+            mutation Rename($input: RenameInput = {name: "}"}) { rename(input: $input) { ...Fields } }
+        "#, "Rename"), OperationType::Mutation);
+        assert_eq!(operation_type("query Get { viewer { id } } mutation Rename { viewer { id } }", "Rename"), OperationType::Mutation);
+        assert_eq!(operation_type("query Get { viewer { id } } mutation Rename { viewer { id } }", "Other"), OperationType::Query);
+    }
+
+    #[tokio::test]
+    async fn test_entity_cache() {
+        let transport = Arc::new(MockTransport::new());
+        transport
+            .respond("GetAccount", json!({"account": {"__typename": "Account", "id": "1", "number": "A-1", "balance": 100}}))
+            .respond("UpdateAccount", json!({"updateAccount": {"__typename": "Account", "id": "1", "number": "A-1", "balance": 20}}))
+            .respond("GetAccountStatus", json!({"account": {"__typename": "Account", "id": "1", "status": "ACTIVE"}}));
+
+        let client = Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(transport.clone())
+            .with_entity_cache(EntityCache::new())
+            .build().unwrap();
+
+        let account: Account = client.new_call("GetAccount", "account", params("A-1"), None).await.unwrap();
+        assert_eq!(account.balance, 100);
+
+        // A mutation returning the same entity updates the cached query
+        let _: Account = client.new_mutation("UpdateAccount", "updateAccount", params("A-1"), None).await.unwrap();
+
+        let account: Account = client.new_call("GetAccount", "account", params("A-1"), None).await.unwrap();
+        assert_eq!(account.balance, 20);
+        assert_eq!(transport.requests().len(), 2);
+
+        // A selection with a field which isn't cached goes to the server, then is merged into the entity
+        let status: AccountStatus = client.new_call("GetAccountStatus", "account", params("A-1"), None).await.unwrap();
+        assert_eq!(status.status, "ACTIVE");
+        assert_eq!(transport.requests().len(), 3);
+
+        let entity = client.entity_cache().unwrap().entity("Account", "1").unwrap();
+        assert_eq!(entity["balance"], json!(20));
+        assert_eq!(entity["status"], json!("ACTIVE"));
+
+        // Different arguments are a different root field
+        transport.respond("GetOtherAccount", json!({"account": {"__typename": "Account", "id": "2", "number": "A-2", "balance": 5}}));
+        let account: Account = client.new_call("GetOtherAccount", "account", params("A-2"), None).await.unwrap();
+        assert_eq!(account.number, "A-2");
+        assert_eq!(transport.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_entity_cache_ttl() {
+        let transport = Arc::new(MockTransport::new());
+        transport
+            .respond("GetAccount", json!({"account": {"__typename": "Account", "id": "1", "number": "A-1", "balance": 100}}))
+            .respond("GetAccount", json!({"account": {"__typename": "Account", "id": "1", "number": "A-1", "balance": 80}}));
+
+        let client = Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(transport.clone())
+            .with_entity_cache(EntityCache::new().with_ttl(Duration::from_millis(50)))
+            .build().unwrap();
+
+        let _: Account = client.new_call("GetAccount", "account", params("A-1"), None).await.unwrap();
+        let account: Account = client.new_call("GetAccount", "account", params("A-1"), None).await.unwrap();
+        assert_eq!(account.balance, 100);
+        assert_eq!(transport.requests().len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        let account: Account = client.new_call("GetAccount", "account", params("A-1"), None).await.unwrap();
+        assert_eq!(account.balance, 80);
+        assert_eq!(transport.requests().len(), 2);
+    }
+}
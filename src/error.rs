@@ -28,6 +28,7 @@ use std::num::{ParseFloatError, ParseIntError};
 use display_json::DisplayAsJsonPretty;
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite;
 
 #[derive(Debug)]
 pub enum Error {
@@ -35,8 +36,13 @@ pub enum Error {
     IOError(reqwest::Error),
     JsonError(serde_json::Error),
     HttpError(StatusCode),
-    InvalidInputError(Box<dyn StdError>),
-    InternalError(String)
+    InvalidInputError(Box<dyn StdError + Send + Sync>),
+    InternalError(String),
+    WebSocketError(Box<tungstenite::Error>),
+    ProtocolError(String),
+    FileError(std::io::Error),
+    CassetteError(String),
+    ConfigurationError(String),
 }
 
 impl Display for Error {
@@ -64,7 +70,12 @@ impl Display for Error {
             Error::JsonError(err) => f.write_fmt(format_args!("JsonError({})", err)),
             Error::HttpError(err) => f.write_fmt(format_args!("HttpError({})", err)),
             Error::InvalidInputError(err) => f.write_fmt(format_args!("InvalidInputError({})", err)),
-            Error::InternalError(err) => f.write_fmt(format_args!("InternalError({})", err))
+            Error::InternalError(err) => f.write_fmt(format_args!("InternalError({})", err)),
+            Error::WebSocketError(err) => f.write_fmt(format_args!("WebSocketError({})", err)),
+            Error::ProtocolError(err) => f.write_fmt(format_args!("ProtocolError({})", err)),
+            Error::FileError(err) => f.write_fmt(format_args!("FileError({})", err)),
+            Error::CassetteError(err) => f.write_fmt(format_args!("CassetteError({})", err)),
+            Error::ConfigurationError(err) => f.write_fmt(format_args!("ConfigurationError({})", err)),
         }
     }
 }
@@ -109,6 +120,18 @@ impl From<reqwest::Error> for Error {
     }
 }
 
+impl From<tungstenite::Error> for Error {
+    fn from(err: tungstenite::Error) -> Error {
+        Error::WebSocketError(Box::new(err))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::FileError(err)
+    }
+}
+
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Error {
         Error::JsonError(err)
@@ -116,23 +139,24 @@ impl From<serde_json::Error> for Error {
 }
 
 
-#[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
+#[derive(Serialize, Deserialize, Debug, Clone, DisplayAsJsonPretty)]
 #[serde(rename_all = "camelCase")]
 pub struct Location {
     pub line: i32,
     pub column: i32,
 }
 
-#[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
+#[derive(Serialize, Deserialize, Debug, Clone, DisplayAsJsonPretty)]
 #[serde(rename_all = "camelCase")]
 pub struct ValidationError {
        pub message: String,
        pub input_path: Vec<String>
 }
 
-#[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, DisplayAsJsonPretty)]
 #[serde(rename_all = "camelCase")]
 pub struct Extensions {
+    pub code: Option<String>,
     pub error_type: Option<String>,
     pub error_code: Option<String>,
     pub error_description: Option<String>,
@@ -140,11 +164,28 @@ pub struct Extensions {
     pub validation_errors: Option<Vec<ValidationError>>
 }
 
-#[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
+/// An element of an error path, a field name or a list index
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum PathSegment {
+    Field(String),
+    Index(usize),
+}
+
+impl PartialEq<str> for PathSegment {
+    fn eq(&self, other: &str) -> bool {
+        matches!(self, PathSegment::Field(field) if field == other)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, DisplayAsJsonPretty)]
 #[serde(rename_all = "camelCase")]
 pub struct GraphQLJsonError {
     pub message: Option<String>,
+    #[serde(default)]
     pub locations: Vec<Location>,
-    pub path: Vec<String>,
+    #[serde(default)]
+    pub path: Vec<PathSegment>,
+    #[serde(default)]
     pub extensions: Extensions,
 }
\ No newline at end of file
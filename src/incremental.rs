@@ -0,0 +1,418 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+//! Incremental delivery of `@defer` and `@stream` results over `multipart/mixed`
+//! see https://github.com/graphql/graphql-over-http/blob/main/rfcs/IncrementalDelivery.md
+
+use futures::{StreamExt, TryStreamExt};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::{Error, GraphQLJsonError, PartialResponse, PathSegment, Subscription, TransportStream};
+
+pub const ACCEPT: &str = "multipart/mixed; deferSpec=20220824, application/json";
+
+/// Incremental parser for a `multipart/mixed` body, returns the body of each part as it completes.
+/// Chunks may split parts anywhere.
+#[derive(Debug)]
+pub struct MultipartParser {
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    started: bool,
+    finished: bool,
+}
+
+impl MultipartParser {
+    pub fn new(boundary: &str) -> MultipartParser {
+        MultipartParser {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // The first delimiter may not have a line break before it
+            buf: b"\r\n".to_vec(),
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// The boundary from a `multipart/mixed` content type, `-` if it does not say
+    pub fn boundary(content_type: &str) -> String {
+        content_type.split(';')
+            .filter_map(|param| param.trim().strip_prefix("boundary="))
+            .map(|boundary| boundary.trim_matches('"').to_string())
+            .next()
+            .unwrap_or_else(|| "-".to_string())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(chunk);
+
+        let mut parts = Vec::new();
+
+        while !self.finished {
+            let pos = match self.buf.windows(self.delimiter.len()).position(|window| window == self.delimiter.as_slice()) {
+                Some(pos) => pos,
+                None => break,
+            };
+
+            // Wait to see whether this is the close delimiter
+            let end = pos + self.delimiter.len();
+            if self.buf.len() < end + 2 {
+                break;
+            }
+
+            let part: Vec<u8> = self.buf.drain(..end).take(pos).collect();
+            self.finished = self.buf.starts_with(b"--");
+
+            if self.started {
+                let part = String::from_utf8_lossy(&part);
+                let body = match part.split_once("\r\n\r\n") {
+                    Some((_headers, body)) => body,
+                    None => "",
+                };
+
+                if !body.trim().is_empty() {
+                    parts.push(body.to_string());
+                }
+            }
+            self.started = true;
+        }
+
+        parts
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Payload {
+    #[serde(default)]
+    data: Option<serde_json::Value>,
+    #[serde(default)]
+    errors: Vec<GraphQLJsonError>,
+    #[serde(default)]
+    incremental: Vec<Incremental>,
+    #[serde(default)]
+    has_next: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct Incremental {
+    #[serde(default)]
+    data: Option<serde_json::Value>,
+    #[serde(default)]
+    items: Option<Vec<serde_json::Value>>,
+    #[serde(default)]
+    path: Vec<PathSegment>,
+    #[serde(default)]
+    errors: Vec<GraphQLJsonError>,
+}
+
+/// The result so far of an operation delivered incrementally, the initial payload with the later ones
+/// merged into it
+#[derive(Debug, Default)]
+pub struct IncrementalResult {
+    data: serde_json::Value,
+    errors: Vec<GraphQLJsonError>,
+    has_next: bool,
+}
+
+impl IncrementalResult {
+    pub fn new() -> IncrementalResult {
+        IncrementalResult::default()
+    }
+
+    /// Merge the next payload. Deferred `data` is merged into the object at its path and streamed `items`
+    /// are appended to the list which contains the index at the end of their path.
+    pub fn apply(&mut self, payload: &str) -> Result<(), Error> {
+        let payload: Payload = serde_json::from_str(payload)?;
+
+        if let Some(data) = payload.data {
+            merge(&mut self.data, data);
+        }
+        self.errors.extend(payload.errors);
+
+        for incremental in payload.incremental {
+            if let Some(data) = incremental.data {
+                let target = find(&mut self.data, &incremental.path)
+                    .ok_or_else(|| Error::ProtocolError(format!("No result at deferred path {:?}", incremental.path)))?;
+                merge(target, data);
+            }
+
+            if let Some(items) = incremental.items {
+                let list_path = &incremental.path[..incremental.path.len().saturating_sub(1)];
+                match find(&mut self.data, list_path) {
+                    Some(serde_json::Value::Array(list)) => list.extend(items),
+                    _ => return Err(Error::ProtocolError(format!("No list at streamed path {:?}", incremental.path))),
+                }
+            }
+
+            self.errors.extend(incremental.errors);
+        }
+
+        self.has_next = payload.has_next;
+        Ok(())
+    }
+
+    pub fn has_next(&self) -> bool {
+        self.has_next
+    }
+
+    pub fn data(&self) -> &serde_json::Value {
+        &self.data
+    }
+
+    /// The root field `query_name` so far, with every error so far. As for other calls a null field is kept
+    /// unless there are errors.
+    pub fn response(&self, query_name: &str) -> PartialResponse<serde_json::Value> {
+        PartialResponse {
+            data: self.data.get(query_name).filter(|value| !value.is_null() || self.errors.is_empty()).cloned(),
+            errors: self.errors.clone(),
+        }
+    }
+}
+
+fn merge(target: &mut serde_json::Value, value: serde_json::Value) {
+    match (target, value) {
+        (serde_json::Value::Object(target), serde_json::Value::Object(value)) => {
+            for (key, value) in value {
+                merge(target.entry(key).or_insert(serde_json::Value::Null), value);
+            }
+        },
+        (target, value) => *target = value,
+    }
+}
+
+fn find<'v>(value: &'v mut serde_json::Value, path: &[PathSegment]) -> Option<&'v mut serde_json::Value> {
+    path.iter().try_fold(value, |value, segment| match segment {
+        PathSegment::Field(name) => value.get_mut(name.as_str()),
+        PathSegment::Index(index) => value.get_mut(*index),
+    })
+}
+
+pub(crate) fn subscribe(response: TransportStream, query_name: &str) -> Subscription<PartialResponse<serde_json::Value>> {
+    let (sender, receiver) = mpsc::channel(16);
+
+    tokio::spawn(run(response, sender, query_name.to_string()));
+
+    Subscription::new(receiver)
+}
+
+async fn run(response: TransportStream, sender: mpsc::Sender<Result<PartialResponse<serde_json::Value>, Error>>, query_name: String) {
+    let content_type = response.headers.get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    let mut result = IncrementalResult::new();
+    let mut body = response.body;
+
+    // A server which does not support incremental delivery sends the whole result at once
+    if !content_type.starts_with("multipart/mixed") {
+        let item = match body.try_concat().await {
+            Ok(body) => result.apply(&String::from_utf8_lossy(&body)).map(|_| result.response(&query_name)),
+            Err(error) => Err(error),
+        };
+        let _ = sender.send(item).await;
+        return;
+    }
+
+    let mut parser = MultipartParser::new(&MultipartParser::boundary(&content_type));
+
+    while !parser.is_finished() {
+        let chunk = tokio::select! {
+            chunk = body.next() => chunk,
+            _ = sender.closed() => return,
+        };
+
+        let chunk = match chunk {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(error)) => {
+                let _ = sender.send(Err(error)).await;
+                return;
+            },
+            None => {
+                let _ = sender.send(Err(Error::ProtocolError("Incremental response ended before its last part".to_string()))).await;
+                return;
+            },
+        };
+
+        for part in parser.push(&chunk) {
+            let item = result.apply(&part).map(|_| result.response(&query_name));
+            let failed = item.is_err();
+
+            if sender.send(item).await.is_err() || failed || !result.has_next() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use reqwest::StatusCode;
+    use serde_json::json;
+
+    use crate::test_server::{StubResponse, StubServer};
+    use crate::{Client, GraphQLType, MockTransport, NoParams, RefreshableToken, RetryPolicy, TransportResponse};
+
+    #[test]
+    fn test_parse_split_chunks() {
+        assert_eq!(MultipartParser::boundary("multipart/mixed; boundary=\"graphql\"; deferSpec=20220824"), "graphql");
+        assert_eq!(MultipartParser::boundary("multipart/mixed"), "-");
+
+        let mut parser = MultipartParser::new("-");
+
+        assert_eq!(parser.push(b"\r\n---\r\nContent-Type: application/json\r\n\r\n{\"a\":"), Vec::<String>::new());
+        assert_eq!(parser.push(b"\"---\"}\r\n---"), Vec::<String>::new());
+        assert_eq!(parser.push(b"\r\nContent-Type: application/json\r\n\r\n{\"b\":1}\r\n-----\r\n"), vec!["{\"a\":\"---\"}", "{\"b\":1}"]);
+        assert!(parser.is_finished());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut result = IncrementalResult::new();
+
+        result.apply(r#"{"data": {"account": {"number": "A-1", "bills": [{"id": "B-1"}]}}, "hasNext": true}"#).unwrap();
+        result.apply(r#"{"incremental": [
+            {"items": [{"id": "B-2"}, {"id": "B-3"}], "path": ["account", "bills", 1]},
+            {"data": {"balance": 10}, "path": ["account"]}
+        ], "hasNext": true}"#).unwrap();
+        result.apply(r#"{"incremental": [
+            {"data": {"amount": 5}, "path": ["account", "bills", 2], "errors": [{"message": "Slow", "path": ["account", "bills", 2, "pdf"]}]}
+        ], "hasNext": false}"#).unwrap();
+
+        assert!(!result.has_next());
+        let response = result.response("account");
+        assert_eq!(response.data.unwrap(), json!({
+            "number": "A-1",
+            "balance": 10,
+            "bills": [{"id": "B-1"}, {"id": "B-2"}, {"id": "B-3", "amount": 5}]
+        }));
+        assert_eq!(response.errors.len(), 1);
+
+        assert!(matches!(result.apply(r#"{"incremental": [{"items": [1], "path": ["missing", 0]}]}"#), Err(Error::ProtocolError(_))));
+    }
+
+    struct Account;
+
+    impl GraphQLType<NoParams> for Account {
+        fn get_query_attributes(_params: &NoParams, _prefix: &str) -> String {
+            "number ... @defer { balance }".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_new_call_incremental() {
+        let body = concat!(
+            "\r\n---\r\nContent-Type: application/json; charset=utf-8\r\n\r\n",
+            "{\"data\":{\"account\":{\"number\":\"A-1\"}},\"hasNext\":true}",
+            "\r\n---\r\nContent-Type: application/json; charset=utf-8\r\n\r\n",
+            "{\"incremental\":[{\"data\":{\"balance\":10},\"path\":[\"account\"]}],\"hasNext\":false}",
+            "\r\n-----\r\n",
+        );
+        let server = StubServer::start(move |_request| StubResponse::new(200, "multipart/mixed; boundary=\"-\"; deferSpec=20220824", body)).await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let updates: Vec<_> = client.new_call_incremental::<Account, _>("GetAccount", "account", NoParams, None).await.unwrap().collect().await;
+
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].as_ref().unwrap().data, Some(json!({"number": "A-1"})));
+        assert_eq!(updates[1].as_ref().unwrap().data, Some(json!({"number": "A-1", "balance": 10})));
+
+        let requests = server.requests();
+        assert_eq!(requests[0].headers["accept"], ACCEPT);
+        assert!(requests[0].json()["query"].as_str().unwrap().contains("@defer"));
+    }
+
+    #[tokio::test]
+    async fn test_new_call_incremental_not_supported() {
+        let server = StubServer::start(|_request| {
+            StubResponse::new(200, "application/json", &json!({"data": {"account": {"number": "A-1", "balance": 10}}}).to_string())
+        }).await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let updates: Vec<_> = client.new_call_incremental::<Account, _>("GetAccount", "account", NoParams, None).await.unwrap().collect().await;
+
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].as_ref().unwrap().data, Some(json!({"number": "A-1", "balance": 10})));
+    }
+
+    #[tokio::test]
+    async fn test_new_call_incremental_truncated() {
+        let body = concat!(
+            "\r\n---\r\nContent-Type: application/json; charset=utf-8\r\n\r\n",
+            "{\"data\":{\"account\":{\"number\":\"A-1\"}},\"hasNext\":true}",
+            "\r\n---\r\n",
+        );
+        let server = StubServer::start(move |_request| StubResponse::new(200, "multipart/mixed; boundary=\"-\"; deferSpec=20220824", body)).await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let updates: Vec<_> = client.new_call_incremental::<Account, _>("GetAccount", "account", NoParams, None).await.unwrap().collect().await;
+
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].as_ref().unwrap().data, Some(json!({"number": "A-1"})));
+        assert!(matches!(updates[1], Err(Error::ProtocolError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_new_call_incremental_transport() {
+        let transport = Arc::new(MockTransport::new());
+        transport
+            .respond_with("GetAccount", TransportResponse::new(StatusCode::SERVICE_UNAVAILABLE, String::new()))
+            .respond_with("GetAccount", TransportResponse::new(StatusCode::UNAUTHORIZED, String::new()))
+            .respond("GetAccount", json!({"account": {"number": "A-1", "balance": 10}}));
+
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let provider = RefreshableToken::new(move || {
+            let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            Box::pin(async move { Ok(format!("token-{}", count)) })
+        });
+
+        let client = Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(transport.clone())
+            .with_retry_policy(RetryPolicy::new().with_initial_backoff(Duration::from_millis(1)))
+            .with_auth_provider(provider)
+            .build().unwrap();
+
+        let updates: Vec<_> = client.new_call_incremental::<Account, _>("GetAccount", "account", NoParams, None).await.unwrap().collect().await;
+
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].as_ref().unwrap().data, Some(json!({"number": "A-1", "balance": 10})));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|request| request.headers.contains(&("Accept".to_string(), ACCEPT.to_string()))));
+        assert!(requests[2].headers.contains(&("Authorization".to_string(), "token-2".to_string())));
+    }
+}
@@ -25,17 +25,61 @@ SOFTWARE.
 pub mod error;
 
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use display_json::DisplayAsJsonPretty;
-use reqwest::StatusCode;
+use futures::StreamExt;
+use reqwest::header::HeaderMap;
+use reqwest::{Method, StatusCode};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::Instrument;
 
-pub use error::{Error, GraphQLJsonError};
+pub use error::{Error, GraphQLJsonError, PathSegment};
 
 
 pub mod types;
 mod traits;
-pub use traits::{ParamBuffer,VariableBuffer,GraphQLQueryParams,GraphQLType, GraphQL, NoParams};
+pub mod subscription;
+pub use subscription::Subscription;
+pub mod sse;
+pub mod incremental;
+pub mod pagination;
+pub mod auth;
+pub mod retry;
+pub use retry::RetryPolicy;
+pub mod redact;
+pub use redact::Redaction;
+pub use auth::{AuthProvider, StaticToken, RefreshableToken, GraphQLTokenProvider};
+pub use pagination::{ForwardPage, ForwardPageParams, BackwardPage, BackwardPageParams, PageLimit};
+pub mod transport;
+pub use transport::{Transport, TransportRequest, TransportResponse, TransportStream, ReqwestTransport, MockTransport, MockRequest};
+use transport::Received;
+pub mod cassette;
+pub use cassette::{CassetteMode, RecordingTransport, ReplayTransport};
+pub mod cache;
+pub use cache::ResponseCache;
+pub mod entity_cache;
+pub use entity_cache::EntityCache;
+pub mod apq;
+pub use apq::PersistedQueries;
+pub mod batch;
+pub use batch::{Batch, BatchHandle, BatchResults};
+pub mod merge;
+pub use merge::{MergedQuery, MergedField, MergedResults};
+pub mod response;
+pub use response::{ErrorPolicy, PartialResponse, ResponseEnvelope, Tracing, QueryCost, ThrottleStatus};
+pub mod rate_limit;
+pub use rate_limit::{RateLimiter, RateLimitBudget};
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod multipart;
+
+#[cfg(test)]
+mod test_server;
+
+pub use traits::{ParamBuffer,VariableBuffer,GraphQLQueryParams,GraphQLType, GraphQL, NoParams, InputParams, OperationType};
 
 
 #[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
@@ -43,20 +87,138 @@ pub use traits::{ParamBuffer,VariableBuffer,GraphQLQueryParams,GraphQLType, Grap
 struct Request<'a, T>
     where T: Serialize
 {
+    /// Empty when only the hash of a persisted query is sent
+    #[serde(skip_serializing_if = "str::is_empty")]
     query:          &'a str,
     variables:      T,
     operation_name:  &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extensions:     Option<serde_json::Value>,
 }
 
 
 
 #[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
 #[serde(rename_all = "camelCase")]
-struct GraphQLResponse {
+pub(crate) struct GraphQLResponse {
    errors: Option<Vec<GraphQLJsonError>>,
-   data:   HashMap<String, serde_json::Value>,
+   /// `None` if the server sent `"data": null`, or no data at all, because of errors
+   #[serde(default)]
+   data:   Option<HashMap<String, serde_json::Value>>,
+   #[serde(default)]
+   extensions: Option<serde_json::Map<String, serde_json::Value>>,
+   #[serde(skip)]
+   headers: HeaderMap,
 }
 
+impl GraphQLResponse {
+    /// Fail with any errors in the response, otherwise decode the result of the root field `query_name`
+    pub(crate) fn into_result<T: DeserializeOwned>(self, query_name: &str) -> Result<T, Error> {
+        self.into_partial(query_name).with_policy(ErrorPolicy::Fail)?.deserialize()?.into_result()
+    }
+
+    pub(crate) fn into_envelope(mut self, query_name: &str) -> ResponseEnvelope<serde_json::Value> {
+        let extensions = self.extensions.take().unwrap_or_default();
+        let headers = std::mem::take(&mut self.headers);
+        let partial = self.into_partial(query_name);
+
+        ResponseEnvelope {
+            data: partial.data,
+            errors: partial.errors,
+            extensions,
+            headers,
+        }
+    }
+
+    /// The result of the root field `query_name` if it is present and the errors. A null result is kept, so
+    /// it decodes as `None`, unless there are errors to say why it is null.
+    pub(crate) fn into_partial(self, query_name: &str) -> PartialResponse<serde_json::Value> {
+        let errors = self.errors.unwrap_or_default();
+
+        PartialResponse {
+            data: self.data
+                .and_then(|mut data| data.remove(query_name))
+                .filter(|value| !value.is_null() || errors.is_empty()),
+            errors,
+        }
+    }
+}
+
+
+/// A response body, either a single response or a batch of them
+pub(crate) trait ResponseBody: DeserializeOwned {
+    /// The errors of each operation in the response
+    fn operation_errors(&self) -> Vec<Vec<&GraphQLJsonError>>;
+
+    fn errors(&self) -> Vec<&GraphQLJsonError> {
+        self.operation_errors().into_iter().flatten().collect()
+    }
+
+    fn set_headers(&mut self, _headers: HeaderMap) {
+    }
+
+    /// The cost of each operation in the response, if the server reported it
+    fn costs(&self) -> Vec<Option<QueryCost>>;
+}
+
+impl ResponseBody for GraphQLResponse {
+    fn operation_errors(&self) -> Vec<Vec<&GraphQLJsonError>> {
+        vec![self.errors.iter().flatten().collect()]
+    }
+
+    fn set_headers(&mut self, headers: HeaderMap) {
+        self.headers = headers;
+    }
+
+    fn costs(&self) -> Vec<Option<QueryCost>> {
+        let cost = self.extensions.as_ref()
+            .and_then(|extensions| QueryCost::from_extensions(extensions).ok().flatten());
+
+        vec![cost]
+    }
+}
+
+impl ResponseBody for Vec<GraphQLResponse> {
+    fn operation_errors(&self) -> Vec<Vec<&GraphQLJsonError>> {
+        self.iter().map(|response| response.errors.iter().flatten().collect()).collect()
+    }
+
+    fn costs(&self) -> Vec<Option<QueryCost>> {
+        self.iter().flat_map(ResponseBody::costs).collect()
+    }
+}
+
+/// The url for a GET request with the members of the JSON `payload` as parameters, objects are JSON encoded.
+/// A batch can't be sent as GET so gives `None`.
+fn get_url(url: &str, payload: &str) -> Result<Option<String>, Error> {
+    let payload: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(payload)? {
+        serde_json::Value::Object(payload) => payload,
+        _ => return Ok(None),
+    };
+
+    let params = payload.iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(value) => (key.as_str(), value.clone()),
+            value => (key.as_str(), value.to_string()),
+        });
+
+    let url = reqwest::Url::parse_with_params(url, params).map_err(|err| Error::InvalidInputError(Box::new(err)))?;
+
+    Ok(Some(url.into()))
+}
+
+fn decode<R: ResponseBody>(response: TransportResponse, redaction: &Redaction) -> Result<R, Error> {
+    tracing::trace!(status = %response.status, body = %redaction.json_str(&response.body), "response");
+
+    if response.status != StatusCode::OK {
+        return Err(Error::HttpError(response.status));
+    }
+
+    let mut graphql_response: R = serde_json::from_str(&response.body)?;
+    graphql_response.set_headers(response.headers);
+
+    Ok(graphql_response)
+}
 
 // #[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
 // #[serde(rename_all = "camelCase")]
@@ -76,10 +238,35 @@ struct GraphQLResponse {
 //    query: Option<Q>
 // }
 
-#[derive(Debug)]
 pub struct Client {
     reqwest_client: reqwest::Client,
+    transport: Arc<dyn Transport>,
     url: String,
+    ws_url: Option<String>,
+    keep_alive: Option<Duration>,
+    ack_timeout: Duration,
+    auth_provider: Option<Arc<dyn AuthProvider>>,
+    retry_policy: Option<RetryPolicy>,
+    redaction: Redaction,
+    cache: Option<Arc<ResponseCache>>,
+    entity_cache: Option<Arc<EntityCache>>,
+    persisted_queries: Option<PersistedQueries>,
+    max_get_url_length: Option<usize>,
+    error_policy: ErrorPolicy,
+    default_headers: Vec<(String, String)>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("url", &self.url)
+            .field("ws_url", &self.ws_url)
+            .field("keep_alive", &self.keep_alive)
+            .field("retry_policy", &self.retry_policy)
+            .field("error_policy", &self.error_policy)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Client {
@@ -88,9 +275,25 @@ impl Client {
     }
 
     pub fn new(url: String) -> Client {
+        let reqwest_client = reqwest::Client::new();
+
         Client {
-            reqwest_client: reqwest::Client::new(),
+            transport: Arc::new(ReqwestTransport::new(reqwest_client.clone())),
+            reqwest_client,
             url,
+            ws_url: None,
+            keep_alive: None,
+            ack_timeout: subscription::DEFAULT_ACK_TIMEOUT,
+            auth_provider: None,
+            retry_policy: None,
+            redaction: Redaction::default(),
+            cache: None,
+            entity_cache: None,
+            persisted_queries: None,
+            max_get_url_length: None,
+            error_policy: ErrorPolicy::default(),
+            default_headers: Vec::new(),
+            rate_limiter: None,
         }
     }
 
@@ -121,160 +324,590 @@ impl Client {
      */
 
     pub async fn new_call<'h, T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams>(&self, request_name: &str, query_name: &str, params: Q, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<T, Error> {
-        
-        
-        let query = //T::get_query(request_name, &params);
-
-        format!(r#"
-            query {}{} {{
-                {}{} {}
-            }}
-        "#, 
-            request_name,
-            params.get_formal(),
-            query_name,
-            params.get_actual(""),
-            T::get_query_part(&params, "")
-        );
+        self.execute(OperationType::Query, request_name, query_name, &params, headers).await
+    }
 
-        let variables = params.get_variables()?;
+    /// As `new_call` but returns any partial data with the errors, as allowed by the error policy
+    pub async fn new_call_partial<'h, T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams>(&self, request_name: &str, query_name: &str, params: Q, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<PartialResponse<T>, Error> {
+        self.execute_partial::<T, Q>(OperationType::Query, request_name, query_name, &params, headers).await?.deserialize()
+    }
 
-        let payload = Request {
-            query: &query,
-            variables: &variables,
-            operation_name: request_name,
-        };
+    /// Typed equivalent of `new_call` for mutations, generates `mutation Name($...) { field(...) { ... } }`
+    pub async fn new_mutation<'h, T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams>(&self, request_name: &str, query_name: &str, params: Q, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<T, Error> {
+        self.execute(OperationType::Mutation, request_name, query_name, &params, headers).await
+    }
 
-        let serialized = serde_json::to_string(&payload).unwrap();
+    /// Run `new_call` for each of `params`, at most `concurrency` at a time. The results are in the same order
+    /// as the params, each call is retried and rate limited as usual and a failure does not stop the others.
+    pub async fn new_call_all<'h, T, Q, I>(&self, request_name: &str, query_name: &str, params: I, concurrency: usize, headers: Option<&'h HashMap<&'h str, &String>>) -> Vec<Result<T, Error>>
+    where T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams, I: IntoIterator<Item = Q>
+    {
+        futures::stream::iter(params)
+            .map(|params| self.new_call(request_name, query_name, params, headers))
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
 
-        println!("NEW payload {}", &serialized);
-        println!("NEW query {}", &query);
-        println!("NEW variables {}", &variables);
+    /// As `new_call` but returns the whole response including extensions and HTTP headers. The response cache
+    /// is not used and errors are returned in the envelope whatever the error policy.
+    pub async fn new_call_envelope<'h, T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams>(&self, request_name: &str, query_name: &str, params: Q, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<ResponseEnvelope<T>, Error> {
+        let query = T::get_document(OperationType::Query, request_name, query_name, &params);
+        let variables = params.get_variable_map()?;
 
-        let mut request = self.reqwest_client.post(&self.url)
-            .header("Content-Type", "application/json");
+        let envelope = self.send_document(OperationType::Query, request_name, &query, &variables, headers).await?.into_envelope(query_name);
 
-        if let Some(map) = headers {
-            
-            for (key, value) in map {
-                request = request.header(*key, *value);
+        Ok(ResponseEnvelope {
+            data: envelope.data.map(serde_json::from_value).transpose()?,
+            errors: envelope.errors,
+            extensions: envelope.extensions,
+            headers: envelope.headers,
+        })
+    }
+
+    /// Errors are handled according to the error policy, with `ErrorPolicy::All` any data is returned and the
+    /// errors are only logged
+    async fn execute<'h, T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams>(&self, operation_type: OperationType, request_name: &str, query_name: &str, params: &Q, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<T, Error> {
+        let response = self.execute_partial::<T, Q>(operation_type, request_name, query_name, params, headers).await?;
+
+        if response.has_errors() {
+            tracing::debug!(operation = request_name, error_count = response.errors.len(), "returning partial data");
+        }
+
+        response.deserialize()?.into_result()
+    }
+
+    async fn execute_partial<'h, T: GraphQLType<Q>, Q: GraphQLQueryParams>(&self, operation_type: OperationType, request_name: &str, query_name: &str, params: &Q, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<PartialResponse<serde_json::Value>, Error> {
+        let query = T::get_document(operation_type, request_name, query_name, params);
+
+        let (variables, uploads) = params.get_variable_buffer()?.into_parts();
+
+        let cache = match (&self.cache, operation_type, uploads.is_empty()) {
+            (Some(cache), OperationType::Query, true) => Some((cache, cache::cache_key(&query, &variables, &self.identity_headers(cache, headers).await?))),
+            _ => None,
+        };
+
+        if let Some((cache, key)) = &cache {
+            if let Some(value) = cache.get(key) {
+                tracing::debug!(operation = request_name, "cache hit");
+                return Ok(PartialResponse { data: Some(value), errors: Vec::new() });
             }
         }
-        
-        let response = request
-            .body(serialized)
-            .send()
-            .await?;
 
-        println!("\nStatus:   {:?}", &response.status());
+        if let (Some(entity_cache), OperationType::Query, true) = (&self.entity_cache, operation_type, uploads.is_empty()) {
+            if let Some(value) = entity_cache.read(&query, &variables, query_name) {
+                tracing::debug!(operation = request_name, "entity cache hit");
+                return Ok(PartialResponse { data: Some(value), errors: Vec::new() });
+            }
+        }
 
-        if &response.status() != &StatusCode::OK {
-            let status = response.status();
-            let text = &(response).text().await;
-            println!("ERROR {}", text.as_ref().expect("No Response Body"));
-            return Err(Error::HttpError(status));
+        let response = if uploads.is_empty() {
+            self.send_document(operation_type, request_name, &query, &variables, headers).await?
         }
+        else {
+            self.post_upload(operation_type, request_name, &query, &variables, &uploads, headers).await?
+        };
+
+        let response = response.into_partial(query_name)
+            .with_policy(self.error_policy)?;
 
-        let response_json: serde_json::Value = response.json().await?;
+        if let (Some(value), false) = (&response.data, response.has_errors()) {
+            if let Some((cache, key)) = cache {
+                cache.insert(request_name, key, &variables, value.clone());
+            }
+
+            if let Some(entity_cache) = &self.entity_cache {
+                entity_cache.write(operation_type, &query, &variables, query_name, value);
+            }
+        }
 
-        println!("response {}", serde_json::to_string_pretty(&response_json)?);
+        Ok(response)
+    }
 
-        let mut graphql_response: GraphQLResponse = serde_json::from_value(response_json)?;
+    /// Start a batch of operations to send in a single request
+    pub fn batch(&self) -> Batch<'_> {
+        Batch::new(self)
+    }
 
+    /// Start a query which combines several root fields into one document
+    pub fn merge(&self, request_name: &str) -> MergedQuery<'_> {
+        MergedQuery::new(self, request_name)
+    }
 
+    /// The response cache, if one was configured, for invalidating entries
+    pub fn cache(&self) -> Option<&ResponseCache> {
+        self.cache.as_deref()
+    }
 
+    /// The normalized entity cache, if one was configured
+    pub fn entity_cache(&self) -> Option<&EntityCache> {
+        self.entity_cache.as_deref()
+    }
 
+    /// The rate limiter, if one was configured, for the remaining budget
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_deref()
+    }
 
-        // let response_json = response.json().await?;
+    /// Start a subscription over the `graphql-transport-ws` protocol, `connection_params` is sent as the payload
+    /// of `connection_init` and is where servers generally expect authentication. If it is `None` the headers
+    /// from the auth provider, if any, are sent instead.
+    pub async fn subscribe<T, Q>(&self, request_name: &str, query_name: &str, params: Q, connection_params: Option<serde_json::Value>) -> Result<Subscription<T>, Error>
+    where T: GraphQLType<Q> + DeserializeOwned + Send + 'static, Q: GraphQLQueryParams
+    {
+        let payload = subscription::SubscribePayload {
+            query: T::get_document(OperationType::Subscription, request_name, query_name, &params),
+            variables: serde_json::Value::Object(params.get_variable_map()?.into_iter().collect()),
+            operation_name: request_name.to_string(),
+        };
 
-        // println!("response {:?}", response_json);
+        let ws_url = match &self.ws_url {
+            Some(ws_url) => ws_url.clone(),
+            None => subscription::ws_url_from(&self.url),
+        };
 
-        // let graphql_response:  GraphQLResponse = response_json;
+        let connection_params = match (connection_params, &self.auth_provider) {
+            (None, Some(auth_provider)) => Some(serde_json::to_value(auth_provider.headers().await?)?),
+            (connection_params, _) => connection_params,
+        };
 
-        if let Some(errors) = graphql_response.errors {
-            
-            println!("\nerrors:   {:?}", serde_json::to_string_pretty(&errors)?);
+        subscription::subscribe(&ws_url, payload, query_name, connection_params, self.ack_timeout, self.keep_alive).await
+    }
 
-            return Err(Error::GraphQLError(errors));
-        }
-        
-        if let Some(response) = graphql_response.data.remove(query_name) {
-            let object: T = serde_json::from_value(response)?;
-            Ok(object)
+    /// Start a subscription over Server-Sent Events using the graphql-sse "distinct connections" mode, each
+    /// subscription is a separate POST whose response is the event stream.
+    pub async fn subscribe_sse<'h, T, Q>(&self, request_name: &str, query_name: &str, params: Q, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<Subscription<T>, Error>
+    where T: GraphQLType<Q> + DeserializeOwned + Send + 'static, Q: GraphQLQueryParams
+    {
+        let query = T::get_document(OperationType::Subscription, request_name, query_name, &params);
+        let variables = params.get_variable_map()?;
+
+        let payload = Request {
+            query: &query,
+            variables: &variables,
+            operation_name: request_name,
+            extensions: None,
+        };
+
+        let mut builder = self.reqwest_client.post(&self.url);
+
+        for (key, value) in self.request_headers(headers).await? {
+            builder = builder.header(key, value);
         }
-        else {
-            return Err(Error::InternalError(format!("No response found")))
+
+        let response = builder
+            .header("Content-Type", "application/json")
+            .header("Accept", sse::CONTENT_TYPE)
+            .body(serde_json::to_string(&payload)?)
+            .send()
+            .await?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Error::HttpError(response.status()));
         }
+
+        Ok(sse::subscribe(response, query_name))
+    }
+
+    /// A query using `@defer` or `@stream`. Each item is the root field merged so far with every error so far,
+    /// if the server does not support incremental delivery there is one item with the whole result. The request
+    /// is retried and its credentials refreshed as for other calls, but not once the response has started.
+    pub async fn new_call_incremental<'h, T, Q>(&self, request_name: &str, query_name: &str, params: Q, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<Subscription<PartialResponse<serde_json::Value>>, Error>
+    where T: GraphQLType<Q>, Q: GraphQLQueryParams
+    {
+        let query = T::get_document(OperationType::Query, request_name, query_name, &params);
+        let variables = params.get_variable_map()?;
+
+        let payload = serde_json::to_string(&Request {
+            query: &query,
+            variables: &variables,
+            operation_name: request_name,
+            extensions: None,
+        })?;
+
+        let request = TransportRequest {
+            method: Method::POST,
+            url: self.url.clone(),
+            operation_name: request_name.to_string(),
+            headers: vec![("Accept".to_string(), incremental::ACCEPT.to_string())],
+            content_type: Some("application/json".to_string()),
+            body: payload.into_bytes(),
+        };
+
+        let span = tracing::info_span!("graphql",
+            operation = request_name,
+            operation_type = %OperationType::Query,
+            status = tracing::field::Empty,
+        );
+
+        let receive = |response: TransportStream| match response.status {
+            StatusCode::OK => Ok(response),
+            status => Err(Error::HttpError(status)),
+        };
+        let is_auth_failure = |_: &dyn AuthProvider, result: &Result<TransportStream, Error>| {
+            matches!(result, Err(Error::HttpError(StatusCode::UNAUTHORIZED)))
+        };
+
+        let response = self.send_with_refresh(&request, &[request_name], headers, self.is_retryable(OperationType::Query, request_name), receive, is_auth_failure)
+            .instrument(span)
+            .await?;
+
+        Ok(incremental::subscribe(response, query_name))
     }
 
     pub async fn call<'h, T>(&self, operation_name: &str, query: &str, variables: &T, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<HashMap<String, serde_json::Value>, Error>
     where T: Serialize
     {
+        let operation_type = entity_cache::operation_type(query, operation_name);
+        let graphql_response = self.send_document(operation_type, operation_name, query, variables, headers).await?;
+
+        match (graphql_response.errors, graphql_response.data) {
+            (Some(errors), data) if self.error_policy == ErrorPolicy::Fail || data.is_none() => Err(Error::GraphQLError(errors)),
+            (_, data) => Ok(data.unwrap_or_default()),
+        }
+    }
+
+    /// Send a query document. If automatic persisted queries are enabled then a document the server is known
+    /// to have is sent by hash alone, otherwise the whole document is sent with its hash, which registers it.
+    async fn send_document<'h, V: Serialize>(&self, operation_type: OperationType, request_name: &str, query: &str, variables: &V, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<GraphQLResponse, Error> {
+        let persisted_queries = self.persisted_queries.as_ref().filter(|persisted_queries| persisted_queries.is_supported());
+        let hash = persisted_queries.map(|_| apq::hash(query));
+
+        if let (Some(persisted_queries), Some(hash)) = (persisted_queries, &hash) {
+            if persisted_queries.is_known(hash) {
+                let payload = Request {
+                    query: "",
+                    variables,
+                    operation_name: request_name,
+                    extensions: Some(apq::extensions(hash)),
+                };
+
+                let response = self.post(operation_type, request_name, serde_json::to_string(&payload)?, headers).await?;
+
+                if apq::is_not_supported(&response) {
+                    tracing::debug!(operation = request_name, "persisted queries not supported");
+                    persisted_queries.set_unsupported();
+                }
+                else if apq::is_not_found(&response) {
+                    tracing::debug!(operation = request_name, hash, "persisted query not found");
+                    persisted_queries.forget(hash);
+                }
+                else {
+                    return Ok(response);
+                }
+            }
+        }
+
+        let registering = persisted_queries.filter(|persisted_queries| persisted_queries.is_supported()).zip(hash);
         let payload = Request {
             query,
             variables,
-            operation_name,
+            operation_name: request_name,
+            extensions: registering.as_ref().map(|(_, hash)| apq::extensions(hash)),
         };
 
-        let serialized = serde_json::to_string(&payload).unwrap();
+        let response: GraphQLResponse = self.post(operation_type, request_name, serde_json::to_string(&payload)?, headers).await?;
 
-        println!("payload {}", &serialized);
+        if let Some((persisted_queries, hash)) = registering {
+            if apq::is_not_supported(&response) {
+                tracing::debug!(operation = request_name, "persisted queries not supported");
+                persisted_queries.set_unsupported();
 
-        let mut request = self.reqwest_client.post(&self.url)
-            .header("Content-Type", "application/json");
+                let payload = Request { extensions: None, ..payload };
+                return self.post(operation_type, request_name, serde_json::to_string(&payload)?, headers).await;
+            }
 
-        if let Some(map) = headers {
-            
-            for (key, value) in map {
-                request = request.header(*key, *value);
+            if response.errors.is_none() {
+                persisted_queries.remember(&hash);
             }
         }
-        
-        let response = request
-            .body(serialized)
-            .send()
-            .await?;
 
-        println!("\nStatus:   {:?}", &response.status());
+        Ok(response)
+    }
 
-        if &response.status() != &StatusCode::OK {
-            let status = response.status();
-            let text = &(response).text().await;
-            println!("ERROR {}", text.as_ref().expect("No Response Body"));
-            return Err(Error::HttpError(status));
+    /// Whether a request may be retried under the retry policy, mutations are only retried if marked idempotent
+    fn is_retryable(&self, operation_type: OperationType, request_name: &str) -> bool {
+        match &self.retry_policy {
+            Some(retry_policy) => operation_type != OperationType::Mutation || retry_policy.is_idempotent_mutation(request_name),
+            None => false,
         }
+    }
 
-        let response_json: serde_json::Value = response.json().await?;
+    /// Send a serialized JSON request
+    async fn post<'h, R: ResponseBody>(&self, operation_type: OperationType, request_name: &str, payload: String, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<R, Error> {
+        let request = self.transport_request(operation_type, request_name, payload)?;
 
-        println!("response {}", serde_json::to_string_pretty(&response_json)?);
+        self.send_request(operation_type, request_name, &[request_name], request, headers).await
+    }
 
-        let graphql_response: GraphQLResponse = serde_json::from_value(response_json)?;
+    /// Send a serialized batch of the operations `operation_names`, named by joining them
+    pub(crate) async fn post_batch<'h, R: ResponseBody>(&self, operation_type: OperationType, operation_names: &[&str], payload: String, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<R, Error> {
+        let request_name = operation_names.join(",");
+        let request = self.transport_request(operation_type, &request_name, payload)?;
 
+        self.send_request(operation_type, &request_name, operation_names, request, headers).await
+    }
 
+    /// Send an operation whose variables contain uploads as a multipart request
+    async fn post_upload<'h>(&self, operation_type: OperationType, request_name: &str, query: &str, variables: &HashMap<String, serde_json::Value>, uploads: &[(String, types::Upload)], headers: Option<&'h HashMap<&'h str, &String>>) -> Result<GraphQLResponse, Error> {
+        let operations = serde_json::to_string(&Request {
+            query,
+            variables,
+            operation_name: request_name,
+            extensions: None,
+        })?;
+        let (content_type, body) = multipart::body(&operations, uploads)?;
+
+        let request = TransportRequest {
+            method: Method::POST,
+            url: self.url.clone(),
+            operation_name: request_name.to_string(),
+            headers: Vec::new(),
+            content_type: Some(content_type),
+            body,
+        };
+
+        self.send_request(operation_type, request_name, &[request_name], request, headers).await
+    }
 
+    /// Send a request in a tracing span for the operation. If the auth provider says it failed because the
+    /// credentials have expired then refresh them and try once more.
+    async fn send_request<'h, R: ResponseBody>(&self, operation_type: OperationType, request_name: &str, operation_names: &[&str], request: TransportRequest, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<R, Error> {
+        let span = tracing::info_span!("graphql",
+            operation = request_name,
+            operation_type = %operation_type,
+            status = tracing::field::Empty,
+            error_count = tracing::field::Empty,
+            latency_ms = tracing::field::Empty,
+        );
+        let start = Instant::now();
 
+        let result = async {
+            if tracing::enabled!(tracing::Level::TRACE) {
+                let payload = request.payload().map(|payload| self.redaction.json(&payload)).unwrap_or_default();
+                tracing::trace!(%payload, content_type = request.content_type.as_deref(), "request");
+            }
 
-        // let response_json = response.json().await?;
+            let retryable = self.is_retryable(operation_type, request_name);
+            let receive = |response: TransportResponse| decode(response, &self.redaction);
+            let is_auth_failure = |auth_provider: &dyn AuthProvider, result: &Result<R, Error>| {
+                auth::is_auth_failure(auth_provider, operation_type, result)
+            };
 
-        // println!("response {:?}", response_json);
+            self.send_with_refresh(&request, operation_names, headers, retryable, receive, is_auth_failure).await
+        }.instrument(span.clone()).await;
 
-        // let graphql_response:  GraphQLResponse = response_json;
+        if let (Some(rate_limiter), Ok(response)) = (&self.rate_limiter, &result) {
+            for (operation_name, cost) in operation_names.iter().zip(response.costs()) {
+                if let Some(cost) = cost {
+                    rate_limiter.observe_cost(operation_name, cost);
+                }
+            }
+        }
 
-        if let Some(errors) = graphql_response.errors {
-            
-            println!("\nerrors:   {:?}", serde_json::to_string_pretty(&errors)?);
+        let error_count = match &result {
+            Ok(response) => response.errors().len(),
+            Err(Error::GraphQLError(errors)) => errors.len(),
+            Err(_) => 0,
+        };
+
+        span.record("error_count", error_count);
+        span.record("latency_ms", start.elapsed().as_millis() as u64);
+
+        span.in_scope(|| match &result {
+            Ok(_) => tracing::debug!(error_count, "complete"),
+            Err(error) => tracing::debug!(%error, "failed"),
+        });
+
+        result
+    }
+
+    /// Queries go as GET if the client is configured to and the url is short enough, everything else is POSTed
+    fn transport_request(&self, operation_type: OperationType, request_name: &str, payload: String) -> Result<TransportRequest, Error> {
+        if let (Some(max_url_length), OperationType::Query) = (self.max_get_url_length, operation_type) {
+            if let Some(url) = get_url(&self.url, &payload)?.filter(|url| url.len() <= max_url_length) {
+                return Ok(TransportRequest {
+                    method: Method::GET,
+                    url,
+                    operation_name: request_name.to_string(),
+                    headers: Vec::new(),
+                    content_type: None,
+                    body: Vec::new(),
+                });
+            }
+
+            tracing::debug!("not sent as GET, using POST");
+        }
+
+        Ok(TransportRequest {
+            method: Method::POST,
+            url: self.url.clone(),
+            operation_name: request_name.to_string(),
+            headers: Vec::new(),
+            content_type: Some("application/json".to_string()),
+            body: payload.into_bytes(),
+        })
+    }
+
+    /// Send with retries and turn the response into `R` with `receive`. If the auth provider says the result is
+    /// an `is_auth_failure` then refresh the credentials and send once more.
+    async fn send_with_refresh<'h, X, R, F, A>(&self, request: &TransportRequest, operation_names: &[&str], headers: Option<&'h HashMap<&'h str, &String>>, retryable: bool, receive: F, is_auth_failure: A) -> Result<R, Error>
+    where X: Received, F: Fn(X) -> Result<R, Error>, A: Fn(&dyn AuthProvider, &Result<R, Error>) -> bool
+    {
+        let (response, sent_auth) = self.post_with_retry(request, operation_names, headers, retryable).await?;
+        let result = receive(response);
+
+        if let Some(auth_provider) = &self.auth_provider {
+            if is_auth_failure(auth_provider.as_ref(), &result) && auth_provider.refresh(&sent_auth).await? {
+                tracing::debug!("retrying with refreshed credentials");
+                return self.post_with_retry(request, operation_names, headers, retryable).await.and_then(|(response, _)| receive(response));
+            }
+        }
+
+        result
+    }
+
+    /// The response and the auth headers the last attempt was sent with
+    async fn post_with_retry<'h, X: Received>(&self, request: &TransportRequest, operation_names: &[&str], headers: Option<&'h HashMap<&'h str, &String>>, retryable: bool) -> Result<(X, HashMap<String, String>), Error> {
+        let mut attempt = 1;
+
+        loop {
+            let result = self.post_once(request, operation_names, headers).await;
+
+            let delay = match &self.retry_policy {
+                Some(retry_policy) if retryable => retry_policy.retry_delay(attempt, result.as_ref().map(|(response, _)| response)),
+                _ => None,
+            };
+
+            match delay {
+                Some(delay) => {
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                },
+                None => return result,
+            }
+        }
+    }
+
+    /// Send once, counting each of `operation_names` against the rate limit
+    async fn post_once<'h, X: Received>(&self, request: &TransportRequest, operation_names: &[&str], headers: Option<&'h HashMap<&'h str, &String>>) -> Result<(X, HashMap<String, String>), Error> {
+        let mut request = request.clone();
+        let auth = self.auth_headers().await?;
+
+        if let Some(content_type) = &request.content_type {
+            request.headers.push(("Content-Type".to_string(), content_type.clone()));
+        }
+        request.headers.extend(self.headers_with_auth(&auth, headers));
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire(operation_names).await;
+        }
+
+        let response = X::send(self.transport.as_ref(), request).await?;
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.observe_headers(response.headers());
+        }
+
+        tracing::Span::current().record("status", response.status().as_u16());
+
+        Ok((response, auth))
+    }
 
-            return Err(Error::GraphQLError(errors));
+    /// The headers which identify the caller to the response cache, the auth provider's and then the default
+    /// and per call headers the cache says are identity headers
+    async fn identity_headers<'h>(&self, cache: &ResponseCache, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<Vec<(String, String)>, Error> {
+        let mut identity: Vec<(String, String)> = self.auth_headers().await?.into_iter().collect();
+
+        let others = self.default_headers.iter()
+            .cloned()
+            .chain(headers.into_iter().flatten().map(|(key, value)| (key.to_string(), value.to_string())));
+
+        identity.extend(others.filter(|(key, _)| cache.is_identity_header(key)));
+
+        Ok(identity)
+    }
+
+    /// The default headers, the auth provider headers and then the per call headers
+    async fn request_headers<'h>(&self, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<Vec<(String, String)>, Error> {
+        Ok(self.headers_with_auth(&self.auth_headers().await?, headers))
+    }
+
+    async fn auth_headers(&self) -> Result<HashMap<String, String>, Error> {
+        match &self.auth_provider {
+            Some(auth_provider) => auth_provider.headers().await,
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// The default headers, the `auth` headers and then the per call headers
+    fn headers_with_auth<'h>(&self, auth: &HashMap<String, String>, headers: Option<&'h HashMap<&'h str, &String>>) -> Vec<(String, String)> {
+        let mut request_headers = self.default_headers.clone();
+
+        for (key, value) in auth {
+            tracing::trace!(header = %key, value = %self.redaction.header_value(key, value), "auth header");
+            request_headers.push((key.clone(), value.clone()));
+        }
+
+        if let Some(map) = headers {
+            
+            for (key, value) in map {
+                tracing::trace!(header = %key, value = %self.redaction.header_value(key, value), "header");
+                request_headers.push((key.to_string(), value.to_string()));
+            }
         }
-        
-        
-        Ok(graphql_response.data)               
+
+        request_headers
     }
 }
 
-#[derive(Debug)]
 pub struct ClientBuilder {
-    url:                Option<String>
+    url:                Option<String>,
+    ws_url:             Option<String>,
+    keep_alive:         Option<Duration>,
+    ack_timeout:        Duration,
+    auth_provider:      Option<Arc<dyn AuthProvider>>,
+    retry_policy:       Option<RetryPolicy>,
+    redaction:          Redaction,
+    transport:          Option<Arc<dyn Transport>>,
+    cassette:           Option<CassetteMode>,
+    cache:              Option<Arc<ResponseCache>>,
+    entity_cache:       Option<Arc<EntityCache>>,
+    persisted_queries:  bool,
+    max_get_url_length: Option<usize>,
+    error_policy:       ErrorPolicy,
+    rate_limiter:       Option<RateLimiter>,
+    default_headers:    Vec<(String, String)>,
+    connect_timeout:    Option<Duration>,
+    timeout:            Option<Duration>,
+    proxy:              Option<reqwest::Proxy>,
+    user_agent:         Option<String>,
+    gzip:               bool,
+    brotli:             bool,
+    pool_max_idle_per_host: Option<usize>,
+    root_certificates:  Vec<reqwest::Certificate>,
+    identity:           Option<reqwest::Identity>,
+}
+
+impl fmt::Debug for ClientBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientBuilder")
+            .field("url", &self.url)
+            .field("ws_url", &self.ws_url)
+            .field("keep_alive", &self.keep_alive)
+            .field("retry_policy", &self.retry_policy)
+            .field("cassette", &self.cassette)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("timeout", &self.timeout)
+            .field("user_agent", &self.user_agent)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ClientBuilder {
@@ -282,21 +915,630 @@ impl ClientBuilder {
     pub fn new() -> ClientBuilder {
         ClientBuilder {
             url: None,
+            ws_url: None,
+            keep_alive: None,
+            ack_timeout: subscription::DEFAULT_ACK_TIMEOUT,
+            auth_provider: None,
+            retry_policy: None,
+            redaction: Redaction::default(),
+            transport: None,
+            cassette: None,
+            cache: None,
+            entity_cache: None,
+            persisted_queries: false,
+            max_get_url_length: None,
+            error_policy: ErrorPolicy::default(),
+            rate_limiter: None,
+            default_headers: Vec::new(),
+            connect_timeout: None,
+            timeout: None,
+            proxy: None,
+            user_agent: None,
+            gzip: true,
+            brotli: true,
+            pool_max_idle_per_host: None,
+            root_certificates: Vec::new(),
+            identity: None,
         }
     }
+
     pub fn with_url(mut self, url: String) -> Result<ClientBuilder, Error> {
         self.url = Some(url);
         Ok(self)
     }
     
     pub fn with_url_if_not_set(mut self, url: String) -> Result<ClientBuilder, Error> {
-        if self.url == None {
+        if self.url.is_none() {
             self.url = Some(url);
         }
         Ok(self)
     }
 
-    pub fn build(self) -> Result<Client, Error> {
-        Ok(Client::new(self.url.unwrap()))
+    /// The WebSocket endpoint for subscriptions, if not set it is derived from the url
+    pub fn with_ws_url(mut self, ws_url: String) -> ClientBuilder {
+        self.ws_url = Some(ws_url);
+        self
+    }
+
+    /// Send a ping on subscription sockets at this interval, the subscription fails if there has been no
+    /// reply by the next one
+    pub fn with_keep_alive(mut self, keep_alive: Duration) -> ClientBuilder {
+        self.keep_alive = Some(keep_alive);
+        self
+    }
+
+    /// How long to wait for `connection_ack` when starting a subscription, by default 10 seconds
+    pub fn with_ack_timeout(mut self, ack_timeout: Duration) -> ClientBuilder {
+        self.ack_timeout = ack_timeout;
+        self
+    }
+
+    /// Supply authentication headers for every request from `auth_provider`
+    pub fn with_auth_provider<A: AuthProvider + 'static>(mut self, auth_provider: A) -> ClientBuilder {
+        self.auth_provider = Some(Arc::new(auth_provider));
+        self
+    }
+
+    /// Retry requests which fail for transient reasons according to `retry_policy`
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> ClientBuilder {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    /// Which headers and variables are masked in trace level logs, by default `Redaction::default()`
+    pub fn with_redaction(mut self, redaction: Redaction) -> ClientBuilder {
+        self.redaction = redaction;
+        self
+    }
+
+    /// Send queries and mutations with `transport` instead of the default `ReqwestTransport`, subscriptions
+    /// still use their own connections
+    pub fn with_transport<T: Transport + 'static>(mut self, transport: T) -> ClientBuilder {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Record traffic to, or replay it from, a cassette file. Recording wraps whichever transport is in use
+    /// and scrubs request headers and variables with the client's `Redaction`.
+    pub fn with_cassette(mut self, cassette: CassetteMode) -> ClientBuilder {
+        self.cassette = Some(cassette);
+        self
+    }
+
+    /// Cache the results of `new_call` queries in `cache`
+    pub fn with_cache(mut self, cache: ResponseCache) -> ClientBuilder {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Normalize the results of `new_call` and `new_mutation` into `entity_cache` and answer queries from
+    /// it when every selected field is present
+    pub fn with_entity_cache(mut self, entity_cache: EntityCache) -> ClientBuilder {
+        self.entity_cache = Some(Arc::new(entity_cache));
+        self
+    }
+
+    /// Use the automatic persisted queries protocol, sending a hash of each document in place of the document
+    /// once the server has it
+    pub fn with_persisted_queries(mut self, persisted_queries: bool) -> ClientBuilder {
+        self.persisted_queries = persisted_queries;
+        self
+    }
+
+    /// Send queries as GET requests so they can be cached by the HTTP layer, falling back to POST when the
+    /// url would be longer than `max_url_length`. Mutations are always POSTed.
+    pub fn with_get_queries(mut self, max_url_length: usize) -> ClientBuilder {
+        self.max_get_url_length = Some(max_url_length);
+        self
+    }
+
+    /// How responses with errors are handled, by default `ErrorPolicy::Fail`
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> ClientBuilder {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Throttle requests with a token bucket and the budget the server reports
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> ClientBuilder {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// A header sent with every request, before the auth provider and per call headers
+    pub fn with_default_header(mut self, name: &str, value: &str) -> Result<ClientBuilder, Error> {
+        reqwest::header::HeaderName::from_bytes(name.as_bytes()).map_err(|err| Error::ConfigurationError(format!("Invalid header name {}: {}", name, err)))?;
+        reqwest::header::HeaderValue::from_str(value).map_err(|err| Error::ConfigurationError(format!("Invalid value for header {}: {}", name, err)))?;
+
+        self.default_headers.push((name.to_string(), value.to_string()));
+        Ok(self)
+    }
+
+    /// The time allowed to establish a connection
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> ClientBuilder {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// The time allowed for each request from connecting until the response body has been read
+    pub fn with_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Send all requests through the HTTP proxy at `proxy_url`
+    pub fn with_proxy(mut self, proxy_url: &str) -> Result<ClientBuilder, Error> {
+        self.proxy = Some(reqwest::Proxy::all(proxy_url)?);
+        Ok(self)
+    }
+
+    pub fn with_user_agent(mut self, user_agent: &str) -> ClientBuilder {
+        self.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    /// Accept gzip compressed responses, on by default
+    pub fn with_gzip(mut self, gzip: bool) -> ClientBuilder {
+        self.gzip = gzip;
+        self
+    }
+
+    /// Accept brotli compressed responses, on by default
+    pub fn with_brotli(mut self, brotli: bool) -> ClientBuilder {
+        self.brotli = brotli;
+        self
+    }
+
+    /// The maximum number of idle connections kept open to the server
+    pub fn with_pool_max_idle_per_host(mut self, pool_max_idle_per_host: usize) -> ClientBuilder {
+        self.pool_max_idle_per_host = Some(pool_max_idle_per_host);
+        self
+    }
+
+    /// Trust a PEM encoded root certificate as well as the system ones
+    pub fn with_root_certificate_pem(mut self, pem: &[u8]) -> Result<ClientBuilder, Error> {
+        self.root_certificates.push(reqwest::Certificate::from_pem(pem)?);
+        Ok(self)
+    }
+
+    /// Present a client certificate for mutual TLS, from a PEM certificate chain and PKCS#8 PEM private key
+    pub fn with_client_certificate_pem(mut self, certificate: &[u8], key: &[u8]) -> Result<ClientBuilder, Error> {
+        self.identity = Some(reqwest::Identity::from_pkcs8_pem(certificate, key)?);
+        Ok(self)
+    }
+
+    fn build_reqwest_client(&mut self) -> Result<reqwest::Client, Error> {
+        let mut builder = reqwest::Client::builder()
+            .gzip(self.gzip)
+            .brotli(self.brotli);
+
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(proxy) = self.proxy.take() {
+            builder = builder.proxy(proxy);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        if let Some(pool_max_idle_per_host) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(pool_max_idle_per_host);
+        }
+        for certificate in self.root_certificates.drain(..) {
+            builder = builder.add_root_certificate(certificate);
+        }
+        if let Some(identity) = self.identity.take() {
+            builder = builder.identity(identity);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// Build a synchronous client, which runs this one on a runtime of its own
+    #[cfg(feature = "blocking")]
+    pub fn build_blocking(self) -> Result<blocking::Client, Error> {
+        blocking::Client::from_async(self.build()?)
+    }
+
+    pub fn build(mut self) -> Result<Client, Error> {
+        let url = self.url.take().ok_or_else(|| Error::ConfigurationError("No url set".to_string()))?;
+
+        for url in std::iter::once(&url).chain(&self.ws_url) {
+            reqwest::Url::parse(url).map_err(|err| Error::ConfigurationError(format!("Invalid url {}: {}", url, err)))?;
+        }
+
+        let reqwest_client = self.build_reqwest_client()?;
+
+        let mut client = Client::new(url);
+        client.transport = Arc::new(ReqwestTransport::new(reqwest_client.clone()));
+        client.reqwest_client = reqwest_client;
+        client.default_headers = self.default_headers;
+        client.ws_url = self.ws_url;
+        client.keep_alive = self.keep_alive;
+        client.ack_timeout = self.ack_timeout;
+        client.auth_provider = self.auth_provider;
+        client.retry_policy = self.retry_policy;
+        client.redaction = self.redaction;
+        client.cache = self.cache;
+        client.entity_cache = self.entity_cache;
+        client.persisted_queries = self.persisted_queries.then(PersistedQueries::new);
+        client.max_get_url_length = self.max_get_url_length;
+        client.error_policy = self.error_policy;
+        client.rate_limiter = self.rate_limiter.map(Arc::new);
+        if let Some(transport) = self.transport {
+            client.transport = transport;
+        }
+        match self.cassette {
+            Some(CassetteMode::Record(path)) => {
+                client.transport = Arc::new(RecordingTransport::new(client.transport.clone(), path).with_redaction(client.redaction.clone()));
+            },
+            Some(CassetteMode::Replay(path)) => {
+                client.transport = Arc::new(ReplayTransport::load(path)?.with_redaction(client.redaction.clone()));
+            },
+            None => {},
+        }
+        Ok(client)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::test_server::{StubResponse, StubServer};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct Account {
+        number: String,
+    }
+
+    impl GraphQLType<InputParams<&str>> for Account {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "number".to_string()
+        }
+    }
+
+    async fn account_server() -> StubServer {
+        StubServer::start(|_request| {
+            StubResponse::new(200, "application/json", &json!({"data": {"account": {"number": "A-1"}}}).to_string())
+        }).await
+    }
+
+    fn get_client(server: &StubServer, max_url_length: usize) -> Client {
+        Client::builder().with_url(server.url.clone()).unwrap()
+            .with_get_queries(max_url_length)
+            .build().unwrap()
+    }
+
+    #[test]
+    fn test_get_url() {
+        let url = get_url("http://localhost/graphql", r#"{"query":"query A { a }","variables":{"x":"a b"},"operationName":"A"}"#).unwrap().unwrap();
+
+        assert_eq!(url, "http://localhost/graphql?operationName=A&query=query+A+%7B+a+%7D&variables=%7B%22x%22%3A%22a+b%22%7D");
+
+        let request = TransportRequest {
+            method: Method::GET,
+            url,
+            operation_name: "A".to_string(),
+            headers: Vec::new(),
+            content_type: None,
+            body: Vec::new(),
+        };
+
+        assert_eq!(request.payload().unwrap(), json!({"query": "query A { a }", "variables": {"x": "a b"}, "operationName": "A"}));
+        assert!(get_url("http://localhost/graphql", "[]").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_query() {
+        let server = account_server().await;
+        let client = get_client(&server, 2048);
+
+        let account: Account = client.new_call("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1"), None).await.unwrap();
+        assert_eq!(account.number, "A-1");
+
+        let _: Account = client.new_mutation("UpdateAccount", "account", InputParams::new("accountNumber", "String!", "A-1"), None).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "GET");
+        assert!(requests[0].path.starts_with("/graphql?operationName=GetAccount&query="));
+        assert!(requests[0].path.contains("&variables=%7B%22accountNumber%22%3A%22A-1%22%7D"));
+        assert!(requests[0].body.is_empty());
+        assert!(!requests[0].headers.contains_key("content-type"));
+
+        assert_eq!(requests[1].method, "POST");
+        assert_eq!(requests[1].json()["operationName"], "UpdateAccount");
+    }
+
+    async fn partial_server() -> StubServer {
+        StubServer::start(|request| {
+            let body = match request.json()["operationName"].as_str() {
+                Some("GetAccount") => json!({
+                    "data": {"account": {"number": "A-1"}},
+                    "errors": [{"message": "Bills unavailable", "path": ["account", "bills", 0]}]
+                }),
+                _ => json!({
+                    "data": null,
+                    "errors": [{"message": "Not found", "path": ["account"]}]
+                }),
+            };
+            StubResponse::new(200, "application/json", &body.to_string())
+        }).await
+    }
+
+    fn policy_client(server: &StubServer, error_policy: ErrorPolicy) -> Client {
+        Client::builder().with_url(server.url.clone()).unwrap()
+            .with_error_policy(error_policy)
+            .build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_partial_data() {
+        let server = partial_server().await;
+        let params = || InputParams::new("accountNumber", "String!", "A-1");
+
+        let client = policy_client(&server, ErrorPolicy::Fail);
+        assert!(matches!(client.new_call::<Account, _>("GetAccount", "account", params(), None).await, Err(Error::GraphQLError(_))));
+
+        let client = policy_client(&server, ErrorPolicy::All);
+        let response = client.new_call_partial::<Account, _>("GetAccount", "account", params(), None).await.unwrap();
+        assert_eq!(response.data.unwrap().number, "A-1");
+        assert_eq!(response.errors[0].path, vec![PathSegment::Field("account".to_string()), PathSegment::Field("bills".to_string()), PathSegment::Index(0)]);
+
+        let account: Account = client.new_call("GetAccount", "account", params(), None).await.unwrap();
+        assert_eq!(account.number, "A-1");
+
+        let client = policy_client(&server, ErrorPolicy::Ignore);
+        let response = client.new_call_partial::<Account, _>("GetAccount", "account", params(), None).await.unwrap();
+        assert!(response.data.is_some() && response.errors.is_empty());
+
+        // "data": null is an error whatever the policy
+        match client.new_call_partial::<Account, _>("GetMissing", "account", params(), None).await {
+            Err(Error::GraphQLError(errors)) => assert_eq!(errors[0].message.as_deref(), Some("Not found")),
+            other => panic!("Expected GraphQLError but got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_null_root_field() {
+        let server = StubServer::start(|_request| {
+            StubResponse::new(200, "application/json", &json!({"data": {"account": null}}).to_string())
+        }).await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let account = client.new_call::<Option<Account>, _>("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1"), None).await.unwrap();
+
+        assert!(account.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_query_too_long() {
+        let server = account_server().await;
+        let client = get_client(&server, 64);
+
+        let _: Account = client.new_call("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1"), None).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/graphql");
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        assert!(matches!(Client::builder().build(), Err(Error::ConfigurationError(_))));
+        assert!(matches!(Client::builder().with_url("not a url".to_string()).unwrap().build(), Err(Error::ConfigurationError(_))));
+        assert!(matches!(Client::builder().with_url("http://localhost/graphql".to_string()).unwrap().with_ws_url("not a url".to_string()).build(), Err(Error::ConfigurationError(_))));
+        assert!(matches!(Client::builder().with_default_header("X-Bad\n", "value"), Err(Error::ConfigurationError(_))));
+        assert!(Client::builder().with_root_certificate_pem(b"not a certificate").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_default_headers() {
+        let server = account_server().await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap()
+            .with_default_header("X-Tenant", "acme").unwrap()
+            .with_user_agent("sparko-test/1.0")
+            .with_timeout(Duration::from_secs(5))
+            .with_pool_max_idle_per_host(1)
+            .build().unwrap();
+
+        let request_id = "other".to_string();
+        let headers = HashMap::from([("X-Request-Id", &request_id)]);
+        let _: Account = client.new_call("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1"), Some(&headers)).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].headers["x-tenant"], "acme");
+        assert_eq!(requests[0].headers["x-request-id"], "other");
+        assert_eq!(requests[0].headers["user-agent"], "sparko-test/1.0");
+        assert!(requests[0].headers["accept-encoding"].contains("gzip"));
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        // Accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/graphql", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let client = Client::builder().with_url(url).unwrap()
+            .with_timeout(Duration::from_millis(200))
+            .build().unwrap();
+
+        let result: Result<Account, Error> = client.new_call("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1"), None).await;
+        assert!(result.is_err());
+    }
+
+    #[derive(Serialize, Clone)]
+    #[serde(rename_all = "camelCase")]
+    struct ReadingInput {
+        meter_serial: String,
+        photo: types::Upload,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Reading {
+        id: String,
+    }
+
+    impl<T: Serialize> GraphQLType<InputParams<T>> for Reading {
+        fn get_query_attributes(_params: &InputParams<T>, _prefix: &str) -> String {
+            "id".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let server = StubServer::start(|_request| {
+            StubResponse::new(200, "application/json", &json!({"data": {"submitReading": {"id": "R-1"}}}).to_string())
+        }).await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let input = ReadingInput {
+            meter_serial: "M-1".to_string(),
+            photo: types::Upload::new("meter.jpg", "image/jpeg", "jpeg bytes"),
+        };
+        let reading: Reading = client.new_mutation("SubmitReading", "submitReading", InputParams::new("input", "ReadingInput!", input), None).await.unwrap();
+        assert_eq!(reading.id, "R-1");
+
+        let files = vec![types::Upload::new("a.csv", "text/csv", "1,2"), types::Upload::new("b.csv", "text/csv", "3,4")];
+        let _: Reading = client.new_call("SubmitReading", "submitReading", InputParams::new("files", "[Upload!]!", files), None).await.unwrap();
+
+        let _: Reading = client.new_mutation("SubmitReading", "submitReading", InputParams::new("meterSerial", "String!", "M-1"), None).await.unwrap();
+
+        let requests = server.requests();
+        assert!(requests[0].headers["content-type"].starts_with("multipart/form-data; boundary="));
+        assert!(requests[0].body.contains(r#""variables":{"input":{"meterSerial":"M-1","photo":null}}"#));
+        assert!(requests[0].body.contains(r#"{"0":["variables.input.photo"]}"#));
+        assert!(requests[0].body.contains("filename=\"meter.jpg\"\r\nContent-Type: image/jpeg\r\n\r\njpeg bytes\r\n"));
+
+        assert!(requests[1].body.contains(r#""variables":{"files":[null,null]}"#));
+        assert!(requests[1].body.contains(r#"{"0":["variables.files.0"],"1":["variables.files.1"]}"#));
+
+        assert_eq!(requests[2].headers["content-type"], "application/json");
+    }
+
+    #[tokio::test]
+    async fn test_upload_transport() {
+        let transport = Arc::new(MockTransport::new());
+        transport
+            .respond_with("SubmitReading", TransportResponse::new(StatusCode::SERVICE_UNAVAILABLE, String::new()))
+            .respond("SubmitReading", json!({"submitReading": {"id": "R-1"}}));
+
+        let client = Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(transport.clone())
+            .with_retry_policy(RetryPolicy::new().with_initial_backoff(Duration::from_millis(1)).with_idempotent_mutation("SubmitReading"))
+            .build().unwrap();
+
+        let input = ReadingInput {
+            meter_serial: "M-1".to_string(),
+            photo: types::Upload::new("meter.jpg", "image/jpeg", "jpeg bytes"),
+        };
+        let reading: Reading = client.new_mutation("SubmitReading", "submitReading", InputParams::new("input", "ReadingInput!", input.clone()), None).await.unwrap();
+        assert_eq!(reading.id, "R-1");
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].variables, json!({"input": {"meterSerial": "M-1", "photo": null}}));
+        assert!(requests[1].headers.iter().any(|(key, value)| key == "Content-Type" && value.starts_with("multipart/form-data; boundary=")));
+
+        let result = client.new_call_envelope::<Reading, _>("SubmitReading", "submitReading", InputParams::new("input", "ReadingInput!", input), None).await;
+        assert!(matches!(result, Err(Error::JsonError(_))), "{:?}", result.err());
+        assert_eq!(transport.requests().len(), 2);
+    }
+
+    /// Answers after a delay which is longer for earlier accounts, recording the most requests in flight
+    #[derive(Default)]
+    struct SlowTransport {
+        in_flight: std::sync::atomic::AtomicUsize,
+        max_in_flight: std::sync::atomic::AtomicUsize,
+    }
+
+    impl Transport for SlowTransport {
+        fn send(&self, request: TransportRequest) -> futures::future::BoxFuture<'_, Result<TransportResponse, Error>> {
+            use std::sync::atomic::Ordering;
+
+            Box::pin(async move {
+                let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+
+                let number = request.payload()?["variables"]["accountNumber"].as_str().unwrap().to_string();
+                let index: u64 = number.trim_start_matches("A-").parse().unwrap();
+                tokio::time::sleep(Duration::from_millis(50 - index * 5)).await;
+
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+                let body = match index {
+                    3 => json!({"data": {"account": null}, "errors": [{"message": "Not found", "path": ["account"]}]}),
+                    _ => json!({"data": {"account": {"number": number}}}),
+                };
+                Ok(TransportResponse::new(StatusCode::OK, body.to_string()))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_new_call_all() {
+        let transport = Arc::new(SlowTransport::default());
+        let client = Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(transport.clone())
+            .build().unwrap();
+
+        let numbers: Vec<String> = (0..8).map(|index| format!("A-{}", index)).collect();
+        let params = numbers.iter().map(|number| InputParams::new("accountNumber", "String!", number.as_str()));
+
+        let results = client.new_call_all::<Account, _, _>("GetAccount", "account", params, 3, None).await;
+
+        assert_eq!(results.len(), 8);
+        for (index, result) in results.iter().enumerate() {
+            match (index, result) {
+                (3, Err(Error::GraphQLError(_))) => {},
+                (_, Ok(account)) => assert_eq!(account.number, numbers[index]),
+                other => panic!("Unexpected result {:?}", other),
+            }
+        }
+        assert_eq!(transport.max_in_flight.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let server = StubServer::start(|_request| {
+            StubResponse::new(200, "application/json", &json!({
+                "data": {"account": {"number": "A-1"}},
+                "extensions": {"cost": {
+                    "requestedQueryCost": 10,
+                    "throttleStatus": {"maximumAvailable": 1000.0, "currentlyAvailable": 990.0, "restoreRate": 50.0}
+                }}
+            }).to_string())
+                .with_header("X-RateLimit-Remaining", "4999")
+                .with_header("X-RateLimit-Limit", "5000")
+                .with_header("X-RateLimit-Reset", "3600")
+        }).await;
+
+        let client = Client::builder().with_url(server.url.clone()).unwrap()
+            .with_rate_limiter(RateLimiter::new().with_requests_per(2, Duration::from_millis(400)))
+            .build().unwrap();
+
+        let start = Instant::now();
+        let params = (0..4).map(|_| InputParams::new("accountNumber", "String!", "A-1"));
+        let results = client.new_call_all::<Account, _, _>("GetAccount", "account", params, 4, None).await;
+
+        assert!(results.iter().all(Result::is_ok));
+        // Two go at once, then one every 200ms
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        let budget = client.rate_limiter().unwrap().budget();
+        assert_eq!(budget.requests_remaining, Some(4999));
+        assert_eq!(budget.requests_limit, Some(5000));
+        assert!(budget.cost_available().unwrap() >= 990.0);
+    }
+}
@@ -0,0 +1,270 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+
+use crate::{Client, Error, ErrorPolicy, GraphQL, GraphQLJsonError, GraphQLQueryParams, GraphQLType, OperationType, ParamBuffer, PartialResponse, VariableBuffer};
+
+/// Identifies one root field in a `MergedQuery` and the type its result decodes to
+#[derive(Debug)]
+pub struct MergedField<T> {
+    alias: String,
+    result_type: PhantomData<fn() -> T>,
+}
+
+/// Builds a single query document from several root fields. Each field is aliased as `{query_name}_{n}`
+/// and its variables are prefixed with the alias using `GraphQL::prefix`, so the same field can be
+/// requested more than once with different params.
+pub struct MergedQuery<'a> {
+    client: &'a Client,
+    request_name: String,
+    formal: ParamBuffer,
+    variables: VariableBuffer,
+    fields: Vec<String>,
+}
+
+impl<'a> MergedQuery<'a> {
+    pub(crate) fn new(client: &'a Client, request_name: &str) -> MergedQuery<'a> {
+        MergedQuery {
+            client,
+            request_name: request_name.to_string(),
+            formal: ParamBuffer::new(),
+            variables: VariableBuffer::new(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn field<T: GraphQLType<Q> + DeserializeOwned, Q: GraphQLQueryParams>(&mut self, query_name: &str, params: Q) -> Result<MergedField<T>, Error> {
+        let alias = format!("{}_{}", query_name, self.fields.len());
+        let prefix = GraphQL::prefix("", &alias);
+
+        let mut variables = VariableBuffer::new();
+        params.get_variables_part(&mut variables, &prefix)?;
+
+        if !variables.uploads().is_empty() {
+            return Err(Error::InvalidInputError(crate::traits::UPLOADS_NOT_SUPPORTED.into()));
+        }
+
+        params.get_formal_part(&mut self.formal, &prefix);
+        self.variables.append(variables);
+
+        self.fields.push(format!("{}: {}{} {}", alias, query_name, params.get_actual(&prefix), T::get_query_part(&params, &prefix)));
+
+        Ok(MergedField {
+            alias,
+            result_type: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn get_document(&self) -> String {
+        format!("query {}{} {{\n{}}}\n", self.request_name, self.formal.clone().consume(), self.fields.join(""))
+    }
+
+    /// Send the merged document. This fails only if the request as a whole does, errors for individual
+    /// fields are returned when their results are taken.
+    pub async fn send<'h>(self, headers: Option<&'h HashMap<&'h str, &String>>) -> Result<MergedResults, Error> {
+        let query = self.get_document();
+        let variables = self.variables.into_map()?;
+
+        let response = self.client.send_document(OperationType::Query, &self.request_name, &query, &variables, headers).await?;
+
+        Ok(MergedResults {
+            data: response.data.unwrap_or_default(),
+            errors: response.errors.unwrap_or_default(),
+            error_policy: self.client.error_policy,
+        })
+    }
+}
+
+/// The results of a `MergedQuery`, errors are attributed to fields by the first element of their path and
+/// handled according to the client's error policy, as for `new_call`.
+#[derive(Debug)]
+pub struct MergedResults {
+    data: HashMap<String, serde_json::Value>,
+    errors: Vec<GraphQLJsonError>,
+    error_policy: ErrorPolicy,
+}
+
+impl MergedResults {
+    /// Decode the result of one field. The errors for the field are those whose path is within it, or which
+    /// have no path and so apply to every field.
+    pub fn take<T: DeserializeOwned>(&mut self, field: &MergedField<T>) -> Result<T, Error> {
+        self.take_partial(field)?.into_result()
+    }
+
+    /// As `take` but returns any partial data with the errors, as allowed by the error policy
+    pub fn take_partial<T: DeserializeOwned>(&mut self, field: &MergedField<T>) -> Result<PartialResponse<T>, Error> {
+        let errors: Vec<GraphQLJsonError> = self.errors.iter()
+            .filter(|error| error.path.first().is_none_or(|root| root == field.alias.as_str()))
+            .cloned()
+            .collect();
+
+        let data = self.data.remove(&field.alias)
+            .filter(|value| !value.is_null() || errors.is_empty());
+
+        PartialResponse { data, errors }
+            .with_policy(self.error_policy)?
+            .deserialize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::test_server::{StubResponse, StubServer};
+    use crate::types::Upload;
+    use crate::InputParams;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct Account {
+        number: String,
+    }
+
+    impl GraphQLType<InputParams<&str>> for Account {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "number".to_string()
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct Viewer {
+        id: String,
+    }
+
+    impl GraphQLType<InputParams<&str>> for Viewer {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "id".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merged_query() {
+        let server = StubServer::start(|_request| {
+            StubResponse::new(200, "application/json", &json!({
+                "data": {
+                    "account_0": {"number": "A-1"},
+                    "account_1": null,
+                    "viewer_2": {"id": "V-1"}
+                },
+                "errors": [{"message": "Not found", "path": ["account_1"]}]
+            }).to_string())
+        }).await;
+
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let mut merged = client.merge("Dashboard");
+        let first = merged.field::<Account, _>("account", InputParams::new("accountNumber", "String!", "A-1")).unwrap();
+        let second = merged.field::<Account, _>("account", InputParams::new("accountNumber", "String!", "A-2")).unwrap();
+        let viewer = merged.field::<Viewer, _>("viewer", InputParams::new("id", "ID", "V-1")).unwrap();
+
+        let document = merged.get_document().split_whitespace().collect::<Vec<_>>().join(" ");
+        assert!(document.starts_with("query Dashboard($account_0_accountNumber: String!, $account_1_accountNumber: String!, $viewer_2_id: ID) {"));
+        assert!(document.contains("account_0: account(accountNumber: $account_0_accountNumber) {"));
+        assert!(document.contains("viewer_2: viewer(id: $viewer_2_id) {"));
+
+        let mut results = merged.send(None).await.unwrap();
+
+        assert_eq!(results.take(&viewer).unwrap().id, "V-1");
+        assert_eq!(results.take(&first).unwrap().number, "A-1");
+        assert!(matches!(results.take(&second), Err(Error::GraphQLError(_))));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].json()["variables"], json!({
+            "account_0_accountNumber": "A-1",
+            "account_1_accountNumber": "A-2",
+            "viewer_2_id": "V-1"
+        }));
+    }
+    impl GraphQLType<InputParams<Upload>> for Viewer {
+        fn get_query_attributes(_params: &InputParams<Upload>, _prefix: &str) -> String {
+            "id".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merged_error_policy() {
+        let server = StubServer::start(|_request| {
+            StubResponse::new(200, "application/json", &json!({
+                "data": {"account_0": {"number": "A-1"}, "viewer_1": null},
+                "errors": [
+                    {"message": "Balance unavailable", "path": ["account_0", "balance"]},
+                    {"message": "Not found", "path": ["viewer_1"]}
+                ]
+            }).to_string())
+        }).await;
+
+        for (error_policy, error_count) in [(ErrorPolicy::Fail, None), (ErrorPolicy::Ignore, Some(0)), (ErrorPolicy::All, Some(1))] {
+            let client = Client::builder().with_url(server.url.clone()).unwrap()
+                .with_error_policy(error_policy)
+                .build().unwrap();
+
+            let mut merged = client.merge("Dashboard");
+            let account = merged.field::<Account, _>("account", InputParams::new("accountNumber", "String!", "A-1")).unwrap();
+            let viewer = merged.field::<Viewer, _>("viewer", InputParams::new("id", "ID", "V-1")).unwrap();
+
+            let mut results = merged.send(None).await.unwrap();
+
+            match error_count {
+                None => assert!(matches!(results.take_partial(&account), Err(Error::GraphQLError(_)))),
+                Some(error_count) => {
+                    let response = results.take_partial(&account).unwrap();
+                    assert_eq!(response.data.unwrap().number, "A-1");
+                    assert_eq!(response.errors.len(), error_count);
+                },
+            }
+            assert!(matches!(results.take(&viewer), Err(Error::GraphQLError(_))));
+        }
+    }
+
+    #[test]
+    fn test_merged_upload() {
+        let client = Client::builder().with_url("http://localhost/graphql".to_string()).unwrap().build().unwrap();
+
+        let mut merged = client.merge("Dashboard");
+        let photo = Upload::new("meter.jpg", "image/jpeg", "jpeg bytes");
+        let result = merged.field::<Viewer, _>("viewer", InputParams::new("photo", "Upload!", photo));
+
+        assert!(matches!(result, Err(Error::InvalidInputError(_))));
+        assert!(merged.is_empty());
+        assert!(merged.get_document().starts_with("query Dashboard {"));
+    }
+}
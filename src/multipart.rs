@@ -0,0 +1,108 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+//! The GraphQL multipart request spec, https://github.com/jaydenseric/graphql-multipart-request-spec
+
+use crate::types::Upload;
+use crate::Error;
+
+/// The content type, with its boundary, and body of a multipart request with the `operations` JSON
+/// followed by the `map` and a part for each upload
+pub(crate) fn body(operations: &str, uploads: &[(String, Upload)]) -> Result<(String, Vec<u8>), Error> {
+    let boundary = format!("sparko-graphql-{:016x}", fastrand::u64(..));
+
+    let map: serde_json::Map<String, serde_json::Value> = uploads.iter()
+        .enumerate()
+        .map(|(index, (path, _))| (index.to_string(), serde_json::json!([path])))
+        .collect();
+
+    let mut body = Vec::new();
+
+    push_part(&mut body, &boundary, "Content-Disposition: form-data; name=\"operations\"\r\nContent-Type: application/json", operations.as_bytes());
+    push_part(&mut body, &boundary, "Content-Disposition: form-data; name=\"map\"\r\nContent-Type: application/json", serde_json::to_string(&map)?.as_bytes());
+
+    for (index, (_, upload)) in uploads.iter().enumerate() {
+        let head = format!("Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: {}",
+            index, escape(upload.file_name()), upload.content_type());
+
+        push_part(&mut body, &boundary, &head, upload.content());
+    }
+
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    Ok((format!("multipart/form-data; boundary={}", boundary), body))
+}
+
+/// The `operations` part of a multipart request made by `body`, where it is always the first part
+pub(crate) fn operations<'a>(content_type: &str, body: &'a [u8]) -> Option<&'a [u8]> {
+    let boundary = content_type.split(';').find_map(|param| param.trim().strip_prefix("boundary="))?;
+    let part = body.strip_prefix(format!("--{}\r\n", boundary).as_bytes())?;
+
+    let head_end = find(part, b"\r\n\r\n")?;
+    find(&part[..head_end], b"name=\"operations\"")?;
+
+    let content = &part[head_end + 4..];
+    let end = find(content, format!("\r\n--{}", boundary).as_bytes())?;
+
+    Some(&content[..end])
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn push_part(body: &mut Vec<u8>, boundary: &str, head: &str, content: &[u8]) {
+    body.extend_from_slice(format!("--{}\r\n{}\r\n\r\n", boundary, head).as_bytes());
+    body.extend_from_slice(content);
+    body.extend_from_slice(b"\r\n");
+}
+
+/// Quotes and line breaks would end the filename parameter early
+fn escape(file_name: &str) -> String {
+    file_name.replace('"', "%22").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body() {
+        let uploads = vec![("variables.file".to_string(), Upload::new("a \"b\".csv", "text/csv", "x,y\n"))];
+        let (content_type, body) = body(r#"{"query":"mutation"}"#, &uploads).unwrap();
+
+        let boundary = content_type.strip_prefix("multipart/form-data; boundary=").unwrap();
+        let body = String::from_utf8(body).unwrap();
+        let parts: Vec<&str> = body.split(&format!("--{}", boundary)).collect();
+
+        assert_eq!(parts.len(), 5);
+        assert!(parts[1].ends_with("\r\n\r\n{\"query\":\"mutation\"}\r\n"));
+        assert!(parts[2].ends_with("\r\n\r\n{\"0\":[\"variables.file\"]}\r\n"));
+        assert!(parts[3].contains("name=\"0\"; filename=\"a %22b%22.csv\"\r\nContent-Type: text/csv\r\n\r\nx,y\n\r\n"));
+        assert_eq!(parts[4], "--\r\n");
+
+        assert_eq!(operations(&content_type, body.as_bytes()), Some(&b"{\"query\":\"mutation\"}"[..]));
+        assert_eq!(operations("multipart/form-data; boundary=other", body.as_bytes()), None);
+    }
+}
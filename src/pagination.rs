@@ -0,0 +1,455 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use std::collections::{HashMap, VecDeque};
+
+use futures::Stream;
+use serde::de::DeserializeOwned;
+
+use crate::types::{BackwardPageOf, Connection, ForwardPageOf};
+use crate::{Client, Error, GraphQLQueryParams, GraphQLType, OperationType};
+
+/// Params for a query which returns a forward paginated connection
+pub trait ForwardPageParams: GraphQLQueryParams {
+    /// Set the `after` cursor for the next request
+    fn set_after(&mut self, after: Option<String>);
+}
+
+/// Params for a query which returns a backward paginated connection
+pub trait BackwardPageParams: GraphQLQueryParams {
+    /// Set the `before` cursor for the next request
+    fn set_before(&mut self, before: Option<String>);
+}
+
+/// The result of a query which returns one page of a forward paginated connection.
+///
+/// This is implemented for `ForwardPageOf<T>` and `Connection<T>`, implement it on a wrapper type where the
+/// connection is nested inside the root field.
+pub trait ForwardPage {
+    type Node;
+
+    fn end_cursor(&self) -> Option<&str>;
+    fn has_next_page(&self) -> bool;
+    fn into_nodes(self) -> Vec<Self::Node>;
+}
+
+/// The result of a query which returns one page of a backward paginated connection.
+///
+/// This is implemented for `BackwardPageOf<T>` and `Connection<T>`.
+pub trait BackwardPage {
+    type Node;
+
+    fn start_cursor(&self) -> Option<&str>;
+    fn has_previous_page(&self) -> bool;
+    fn into_nodes(self) -> Vec<Self::Node>;
+}
+
+impl<T> ForwardPage for ForwardPageOf<T> {
+    type Node = T;
+
+    fn end_cursor(&self) -> Option<&str> {
+        self.page_info.end_cursor.as_deref()
+    }
+
+    fn has_next_page(&self) -> bool {
+        self.page_info.has_next_page
+    }
+
+    fn into_nodes(self) -> Vec<T> {
+        ForwardPageOf::into_nodes(self)
+    }
+}
+
+impl<T> BackwardPage for BackwardPageOf<T> {
+    type Node = T;
+
+    fn start_cursor(&self) -> Option<&str> {
+        self.page_info.start_cursor.as_deref()
+    }
+
+    fn has_previous_page(&self) -> bool {
+        self.page_info.has_previous_page
+    }
+
+    fn into_nodes(self) -> Vec<T> {
+        BackwardPageOf::into_nodes(self)
+    }
+}
+
+impl<T, E> ForwardPage for Connection<T, E> {
+    type Node = T;
+
+    fn end_cursor(&self) -> Option<&str> {
+        self.page_info.end_cursor.as_deref()
+    }
+
+    fn has_next_page(&self) -> bool {
+        self.page_info.has_next_page
+    }
+
+    fn into_nodes(self) -> Vec<T> {
+        Connection::into_nodes(self)
+    }
+}
+
+impl<T, E> BackwardPage for Connection<T, E> {
+    type Node = T;
+
+    fn start_cursor(&self) -> Option<&str> {
+        self.page_info.start_cursor.as_deref()
+    }
+
+    fn has_previous_page(&self) -> bool {
+        self.page_info.has_previous_page
+    }
+
+    fn into_nodes(self) -> Vec<T> {
+        Connection::into_nodes(self)
+    }
+}
+
+/// Limits on how much of a connection `Client::paginate` will fetch, by default there is no limit
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PageLimit {
+    max_pages: Option<usize>,
+    max_items: Option<usize>,
+}
+
+impl PageLimit {
+    pub fn new() -> PageLimit {
+        PageLimit::default()
+    }
+
+    pub fn with_max_pages(mut self, max_pages: usize) -> PageLimit {
+        self.max_pages = Some(max_pages);
+        self
+    }
+
+    pub fn with_max_items(mut self, max_items: usize) -> PageLimit {
+        self.max_items = Some(max_items);
+        self
+    }
+}
+
+struct PageState<N, Q> {
+    params: Q,
+    cursor: Option<String>,
+    buffer: VecDeque<N>,
+    pages: usize,
+    items: usize,
+    done: bool,
+    error: Option<Error>,
+}
+
+impl Client {
+    /// Fetch every node of a forward paginated connection, following `endCursor` until `hasNextPage` is false
+    /// or `limit` is reached.
+    pub fn paginate<'a, T, Q>(&'a self, request_name: &'a str, query_name: &'a str, params: Q, headers: Option<&'a HashMap<&'a str, &'a String>>, limit: PageLimit)
+        -> impl Stream<Item = Result<T::Node, Error>> + 'a
+    where T: GraphQLType<Q> + DeserializeOwned + ForwardPage + 'a, Q: ForwardPageParams + 'a
+    {
+        self.page_stream(request_name, query_name, params, headers, limit, |page: T| {
+            let next = page.end_cursor().filter(|_| page.has_next_page()).map(str::to_string);
+            (page.into_nodes(), next)
+        }, Q::set_after)
+    }
+
+    /// Fetch every node of a backward paginated connection, following `startCursor` until `hasPreviousPage` is
+    /// false or `limit` is reached. Pages are returned last page first, nodes within each page are in the order
+    /// the server returns them.
+    pub fn paginate_backward<'a, T, Q>(&'a self, request_name: &'a str, query_name: &'a str, params: Q, headers: Option<&'a HashMap<&'a str, &'a String>>, limit: PageLimit)
+        -> impl Stream<Item = Result<T::Node, Error>> + 'a
+    where T: GraphQLType<Q> + DeserializeOwned + BackwardPage + 'a, Q: BackwardPageParams + 'a
+    {
+        self.page_stream(request_name, query_name, params, headers, limit, |page: T| {
+            let next = page.start_cursor().filter(|_| page.has_previous_page()).map(str::to_string);
+            (page.into_nodes(), next)
+        }, Q::set_before)
+    }
+
+    /// `advance` takes each page and returns its nodes and the cursor for the following page, if there is one,
+    /// which `set_cursor` puts in the params. A cursor which does not change would loop forever so it is an error.
+    #[allow(clippy::too_many_arguments)]
+    fn page_stream<'a, T, Q, N>(&'a self, request_name: &'a str, query_name: &'a str, params: Q, headers: Option<&'a HashMap<&'a str, &'a String>>, limit: PageLimit,
+        advance: fn(T) -> (Vec<N>, Option<String>), set_cursor: fn(&mut Q, Option<String>)) -> impl Stream<Item = Result<N, Error>> + 'a
+    where T: GraphQLType<Q> + DeserializeOwned + 'a, Q: GraphQLQueryParams + 'a, N: 'a
+    {
+        let state = PageState {
+            params,
+            cursor: None,
+            buffer: VecDeque::new(),
+            pages: 0,
+            items: 0,
+            done: false,
+            error: None,
+        };
+
+        futures::stream::unfold(state, move |mut state| async move {
+            loop {
+                if limit.max_items.is_some_and(|max_items| state.items >= max_items) {
+                    return None;
+                }
+
+                if let Some(node) = state.buffer.pop_front() {
+                    state.items += 1;
+                    return Some((Ok(node), state));
+                }
+
+                if let Some(error) = state.error.take() {
+                    return Some((Err(error), state));
+                }
+
+                if state.done || limit.max_pages.is_some_and(|max_pages| state.pages >= max_pages) {
+                    return None;
+                }
+
+                let page: T = match self.execute(OperationType::Query, request_name, query_name, &state.params, headers).await {
+                    Ok(page) => page,
+                    Err(error) => {
+                        state.done = true;
+                        return Some((Err(error), state));
+                    },
+                };
+
+                state.pages += 1;
+
+                let (nodes, next) = advance(page);
+                state.buffer.extend(nodes);
+
+                match next {
+                    Some(cursor) if state.cursor.as_ref() == Some(&cursor) => {
+                        state.done = true;
+                        state.error = Some(Error::ProtocolError(format!("Pagination cursor {} did not advance", cursor)));
+                    },
+                    Some(cursor) => {
+                        set_cursor(&mut state.params, Some(cursor.clone()));
+                        state.cursor = Some(cursor);
+                    },
+                    None => state.done = true,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::StreamExt;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::test_server::{StubResponse, StubServer};
+    use crate::{ParamBuffer, VariableBuffer};
+
+    struct BillParams {
+        first: i32,
+        after: Option<String>,
+    }
+
+    impl GraphQLQueryParams for BillParams {
+        fn get_formal_part(&self, params: &mut ParamBuffer, prefix: &str) {
+            params.push_formal(prefix, "first", "Int");
+            params.push_formal(prefix, "after", "String");
+        }
+
+        fn get_actual_part(&self, params: &mut ParamBuffer, prefix: &str) {
+            params.push_actual(prefix, "first");
+            params.push_actual(prefix, "after");
+        }
+
+        fn get_variables_part(&self, variables: &mut VariableBuffer, prefix: &str) -> Result<(), serde_json::Error> {
+            variables.push_variable(prefix, "first", &self.first)?;
+            variables.push_variable(prefix, "after", &self.after)
+        }
+    }
+
+    impl ForwardPageParams for BillParams {
+        fn set_after(&mut self, after: Option<String>) {
+            self.after = after;
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Bill {
+        id: i32,
+    }
+
+    impl GraphQLType<BillParams> for Bill {
+        fn get_query_attributes(_params: &BillParams, _prefix: &str) -> String {
+            "id".to_string()
+        }
+    }
+
+    /// Serves bills 0..9 in pages of two, the cursor is the id of the last bill on the page
+    async fn bill_server() -> StubServer {
+        StubServer::start(|request| {
+            let variables = &request.json()["variables"];
+            let start = match variables["after"].as_str() {
+                Some(after) => after.parse::<i32>().unwrap() + 1,
+                None => 0,
+            };
+            let end = (start + variables["first"].as_i64().unwrap() as i32).min(10);
+            let edges: Vec<serde_json::Value> = (start..end).map(|id| json!({"node": {"id": id}})).collect();
+
+            StubResponse::new(200, "application/json", &json!({"data": {"bills": {
+                "pageInfo": {"startCursor": start.to_string(), "endCursor": (end - 1).to_string(), "hasNextPage": end < 10},
+                "edges": edges
+            }}}).to_string())
+        }).await
+    }
+
+    async fn fetch(limit: PageLimit) -> (Vec<i32>, usize) {
+        let server = bill_server().await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let ids: Vec<i32> = client.paginate::<ForwardPageOf<Bill>, BillParams>("Bills", "bills", BillParams { first: 2, after: None }, None, limit)
+            .map(|bill| bill.unwrap().id)
+            .collect().await;
+
+        (ids, server.requests().len())
+    }
+
+    #[tokio::test]
+    async fn test_paginate_all() {
+        let (ids, requests) = fetch(PageLimit::new()).await;
+
+        assert_eq!(ids, (0..10).collect::<Vec<i32>>());
+        assert_eq!(requests, 5);
+    }
+
+    #[tokio::test]
+    async fn test_paginate_max_pages() {
+        let (ids, requests) = fetch(PageLimit::new().with_max_pages(2)).await;
+
+        assert_eq!(ids, vec![0, 1, 2, 3]);
+        assert_eq!(requests, 2);
+    }
+
+    #[tokio::test]
+    async fn test_paginate_max_items() {
+        let (ids, requests) = fetch(PageLimit::new().with_max_items(3)).await;
+
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(requests, 2);
+    }
+
+    #[tokio::test]
+    async fn test_paginate_empty() {
+        let server = StubServer::start(|_request| {
+            StubResponse::new(200, "application/json", &json!({"data": {"bills": {
+                "pageInfo": {"startCursor": null, "endCursor": null, "hasNextPage": false},
+                "edges": []
+            }}}).to_string())
+        }).await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let bills: Vec<Result<Bill, Error>> = client.paginate::<ForwardPageOf<Bill>, BillParams>("Bills", "bills", BillParams { first: 2, after: None }, None, PageLimit::new())
+            .collect().await;
+
+        assert!(bills.is_empty());
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_paginate_cursor_not_advancing() {
+        let server = StubServer::start(|_request| {
+            StubResponse::new(200, "application/json", &json!({"data": {"bills": {
+                "pageInfo": {"startCursor": "0", "endCursor": "1", "hasNextPage": true},
+                "edges": [{"node": {"id": 0}}, {"node": {"id": 1}}]
+            }}}).to_string())
+        }).await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let bills: Vec<Result<Bill, Error>> = client.paginate::<ForwardPageOf<Bill>, BillParams>("Bills", "bills", BillParams { first: 2, after: None }, None, PageLimit::new())
+            .collect().await;
+
+        assert_eq!(bills.len(), 5);
+        assert!(matches!(bills.last(), Some(Err(Error::ProtocolError(_)))));
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    struct RecentBillParams {
+        last: i32,
+        before: Option<String>,
+    }
+
+    impl GraphQLQueryParams for RecentBillParams {
+        fn get_formal_part(&self, params: &mut ParamBuffer, prefix: &str) {
+            params.push_formal(prefix, "last", "Int");
+            params.push_formal(prefix, "before", "String");
+        }
+
+        fn get_actual_part(&self, params: &mut ParamBuffer, prefix: &str) {
+            params.push_actual(prefix, "last");
+            params.push_actual(prefix, "before");
+        }
+
+        fn get_variables_part(&self, variables: &mut VariableBuffer, prefix: &str) -> Result<(), serde_json::Error> {
+            variables.push_variable(prefix, "last", &self.last)?;
+            variables.push_variable(prefix, "before", &self.before)
+        }
+    }
+
+    impl BackwardPageParams for RecentBillParams {
+        fn set_before(&mut self, before: Option<String>) {
+            self.before = before;
+        }
+    }
+
+    impl GraphQLType<RecentBillParams> for Bill {
+        fn get_query_attributes(_params: &RecentBillParams, _prefix: &str) -> String {
+            "id".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_paginate_backward() {
+        // Serves bills 0..4 in pages of two from the end, the cursor is the id of the first bill on the page
+        let server = StubServer::start(|request| {
+            let variables = &request.json()["variables"];
+            let end = match variables["before"].as_str() {
+                Some(before) => before.parse::<i32>().unwrap(),
+                None => 5,
+            };
+            let start = (end - variables["last"].as_i64().unwrap() as i32).max(0);
+            let edges: Vec<serde_json::Value> = (start..end).map(|id| json!({"node": {"id": id}})).collect();
+
+            StubResponse::new(200, "application/json", &json!({"data": {"bills": {
+                "pageInfo": {"startCursor": start.to_string(), "endCursor": (end - 1).to_string(), "hasNextPage": false, "hasPreviousPage": start > 0},
+                "edges": edges
+            }}}).to_string())
+        }).await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let ids: Vec<i32> = client.paginate_backward::<Connection<Bill>, RecentBillParams>("Bills", "bills", RecentBillParams { last: 2, before: None }, None, PageLimit::new())
+            .map(|bill| bill.unwrap().id)
+            .collect().await;
+
+        assert_eq!(ids, vec![3, 4, 1, 2, 0]);
+        assert_eq!(server.requests().len(), 3);
+        assert!(server.requests()[0].json()["query"].as_str().unwrap().contains("hasPreviousPage"));
+    }
+}
@@ -0,0 +1,373 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use reqwest::header::HeaderMap;
+
+use crate::{QueryCost, ThrottleStatus};
+
+const REMAINING_HEADERS: [&str; 2] = ["x-ratelimit-remaining", "ratelimit-remaining"];
+const LIMIT_HEADERS: [&str; 2] = ["x-ratelimit-limit", "ratelimit-limit"];
+const RESET_HEADERS: [&str; 2] = ["x-ratelimit-reset", "ratelimit-reset"];
+
+/// Reset values larger than this are unix times rather than a number of seconds from now
+const EPOCH_THRESHOLD: u64 = 1_000_000_000;
+
+/// The server's rate limit budget as last reported, less what has been used since
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimitBudget {
+    /// Requests left in the current window, from the `X-RateLimit-*` or `RateLimit-*` headers
+    pub requests_remaining: Option<u64>,
+    pub requests_limit: Option<u64>,
+    pub requests_reset_at: Option<Instant>,
+    /// Query cost budget from the `cost` extension
+    pub throttle_status: Option<ThrottleStatus>,
+    pub throttle_observed_at: Option<Instant>,
+}
+
+impl RateLimitBudget {
+    /// The query cost available now, allowing for what has been restored since it was reported
+    pub fn cost_available(&self) -> Option<f64> {
+        self.cost_available_at(Instant::now())
+    }
+
+    fn cost_available_at(&self, now: Instant) -> Option<f64> {
+        let throttle_status = self.throttle_status.as_ref()?;
+        let elapsed = self.throttle_observed_at.map(|at| now.duration_since(at).as_secs_f64()).unwrap_or_default();
+
+        Some((throttle_status.currently_available + elapsed * throttle_status.restore_rate).min(throttle_status.maximum_available))
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    tokens: f64,
+    refilled_at: Instant,
+    budget: RateLimitBudget,
+    /// The last requested cost of each operation, used as the estimate for the next one
+    costs: HashMap<String, f64>,
+}
+
+/// Throttles requests on the client before the server does. A token bucket limits the request rate, and
+/// requests wait when the budget reported by the server in rate limit headers or the `cost` extension is
+/// down to its reserve, until the window resets or enough cost has been restored.
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_second: f64,
+    request_reserve: u64,
+    cost_reserve: f64,
+    max_wait: Duration,
+    state: Mutex<State>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new()
+    }
+}
+
+impl RateLimiter {
+    /// No limit on the request rate, only the server's budget is tracked
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            capacity: f64::INFINITY,
+            refill_per_second: 0.0,
+            request_reserve: 0,
+            cost_reserve: 0.0,
+            max_wait: Duration::from_secs(60),
+            state: Mutex::new(State {
+                tokens: 0.0,
+                refilled_at: Instant::now(),
+                budget: RateLimitBudget::default(),
+                costs: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Allow bursts of up to `requests`, refilled evenly over `period`
+    pub fn with_requests_per(mut self, requests: u32, period: Duration) -> RateLimiter {
+        let requests = requests.max(1) as f64;
+
+        self.capacity = requests;
+        self.refill_per_second = requests / period.as_secs_f64().max(f64::EPSILON);
+        self.state.get_mut().unwrap().tokens = requests;
+        self
+    }
+
+    /// Wait for the window to reset once the remaining requests are down to this, by default 0
+    pub fn with_request_reserve(mut self, request_reserve: u64) -> RateLimiter {
+        self.request_reserve = request_reserve;
+        self
+    }
+
+    /// Query cost to keep in hand as well as the estimated cost of the next request
+    pub fn with_cost_reserve(mut self, cost_reserve: f64) -> RateLimiter {
+        self.cost_reserve = cost_reserve;
+        self
+    }
+
+    /// The longest a request will be held, whatever the budget says
+    pub fn with_max_wait(mut self, max_wait: Duration) -> RateLimiter {
+        self.max_wait = max_wait;
+        self
+    }
+
+    pub fn budget(&self) -> RateLimitBudget {
+        self.state.lock().unwrap().budget.clone()
+    }
+
+    /// Wait until a request for the operations `request_names` is allowed, and count it against the budget.
+    /// Each operation in a batch takes a token and adds its estimated cost, the batch is one request to the
+    /// server's request budget.
+    pub(crate) async fn acquire(&self, request_names: &[&str]) {
+        let wait = self.reserve(request_names, Instant::now()).min(self.max_wait);
+
+        if !wait.is_zero() {
+            tracing::debug!(operation = %request_names.join(","), wait_ms = wait.as_millis() as u64, "rate limited");
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take what a request will use and return how long it must wait first. Tokens may go negative so that
+    /// concurrent requests queue behind each other.
+    fn reserve(&self, request_names: &[&str], now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let mut wait = Duration::ZERO;
+
+        if self.capacity.is_finite() {
+            let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * self.refill_per_second).min(self.capacity) - request_names.len() as f64;
+            state.refilled_at = now;
+
+            if state.tokens < 0.0 {
+                wait = wait.max(Duration::from_secs_f64(-state.tokens / self.refill_per_second));
+            }
+        }
+
+        let budget = &mut state.budget;
+
+        if budget.requests_reset_at.is_some_and(|reset_at| reset_at <= now) {
+            budget.requests_remaining = None;
+            budget.requests_reset_at = None;
+        }
+
+        if let Some(remaining) = budget.requests_remaining {
+            if remaining <= self.request_reserve {
+                if let Some(reset_at) = budget.requests_reset_at {
+                    wait = wait.max(reset_at - now);
+                }
+            }
+            budget.requests_remaining = Some(remaining.saturating_sub(1));
+        }
+
+        let estimate: f64 = request_names.iter()
+            .filter_map(|request_name| state.costs.get(*request_name))
+            .sum();
+        let budget = &mut state.budget;
+
+        if let (Some(available), Some(throttle_status)) = (budget.cost_available_at(now), budget.throttle_status.as_mut()) {
+            let needed = estimate + self.cost_reserve;
+
+            if available < needed && throttle_status.restore_rate > 0.0 {
+                wait = wait.max(Duration::from_secs_f64((needed - available) / throttle_status.restore_rate));
+            }
+
+            throttle_status.currently_available = available - estimate;
+            budget.throttle_observed_at = Some(now);
+        }
+
+        wait
+    }
+
+    /// Update the request budget from the rate limit headers of a response
+    pub(crate) fn observe_headers(&self, headers: &HeaderMap) {
+        let header = |names: [&str; 2]| names.iter()
+            .find_map(|name| headers.get(*name))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok());
+
+        let remaining = header(REMAINING_HEADERS);
+        if remaining.is_none() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let budget = &mut state.budget;
+
+        budget.requests_remaining = remaining;
+        budget.requests_limit = header(LIMIT_HEADERS).or(budget.requests_limit);
+        budget.requests_reset_at = header(RESET_HEADERS).map(|reset| Instant::now() + reset_delay(reset));
+    }
+
+    /// Update the cost budget, and the estimate for `request_name`, from the `cost` extension of a response
+    pub(crate) fn observe_cost(&self, request_name: &str, cost: QueryCost) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(requested) = cost.requested_query_cost.or(cost.actual_query_cost) {
+            state.costs.insert(request_name.to_string(), requested);
+        }
+
+        if let Some(throttle_status) = cost.throttle_status {
+            state.budget.throttle_status = Some(throttle_status);
+            state.budget.throttle_observed_at = Some(Instant::now());
+        }
+    }
+}
+
+fn reset_delay(reset: u64) -> Duration {
+    if reset < EPOCH_THRESHOLD {
+        return Duration::from_secs(reset);
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Duration::from_secs(reset).saturating_sub(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use reqwest::header::HeaderValue;
+    use reqwest::StatusCode;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use crate::{Client, GraphQLType, InputParams, MockTransport, TransportResponse};
+
+    #[derive(Deserialize, Debug)]
+    struct Account {
+        number: String,
+    }
+
+    impl GraphQLType<InputParams<&str>> for Account {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "number".to_string()
+        }
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new().with_requests_per(2, Duration::from_secs(1));
+        let now = Instant::now();
+
+        assert_eq!(limiter.reserve(&["A"], now), Duration::ZERO);
+        assert_eq!(limiter.reserve(&["A"], now), Duration::ZERO);
+        assert_eq!(limiter.reserve(&["A"], now), Duration::from_millis(500));
+        assert_eq!(limiter.reserve(&["A"], now), Duration::from_secs(1));
+        assert_eq!(limiter.reserve(&["A"], now + Duration::from_secs(2)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_request_budget() {
+        let limiter = RateLimiter::new().with_request_reserve(1);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("2"));
+        headers.insert("x-ratelimit-limit", HeaderValue::from_static("5000"));
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("30"));
+        limiter.observe_headers(&headers);
+
+        let now = Instant::now();
+        assert_eq!(limiter.reserve(&["A"], now), Duration::ZERO);
+
+        let wait = limiter.reserve(&["A"], now);
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+
+        let budget = limiter.budget();
+        assert_eq!(budget.requests_remaining, Some(0));
+        assert_eq!(budget.requests_limit, Some(5000));
+    }
+
+    #[test]
+    fn test_cost_budget() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        limiter.observe_cost("A", QueryCost {
+            requested_query_cost: Some(100.0),
+            actual_query_cost: Some(80.0),
+            throttle_status: Some(ThrottleStatus {
+                maximum_available: 1000.0,
+                currently_available: 150.0,
+                restore_rate: 50.0,
+            }),
+        });
+
+        assert_eq!(limiter.reserve(&["A"], now), Duration::ZERO);
+        assert_eq!(limiter.reserve(&["A"], now), Duration::from_secs(1));
+        assert_eq!(limiter.reserve(&["B"], now), Duration::from_secs(1));
+        assert_eq!(limiter.budget().cost_available_at(now), Some(-50.0));
+
+        assert_eq!(reset_delay(30), Duration::from_secs(30));
+        assert_eq!(reset_delay(EPOCH_THRESHOLD), Duration::ZERO);
+    }
+    #[test]
+    fn test_batch_reserve() {
+        let limiter = RateLimiter::new().with_requests_per(2, Duration::from_secs(1));
+        let now = Instant::now();
+
+        assert_eq!(limiter.reserve(&["A", "B"], now), Duration::ZERO);
+        assert_eq!(limiter.reserve(&["A", "B"], now), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_batch_costs() {
+        let cost = |requested: f64| json!({"cost": {
+            "requestedQueryCost": requested,
+            "throttleStatus": {"maximumAvailable": 1000.0, "currentlyAvailable": 900.0, "restoreRate": 50.0}
+        }});
+
+        let transport = Arc::new(MockTransport::new());
+        transport
+            .respond_with("GetAccount", TransportResponse::new(StatusCode::OK, json!({"data": {"account": {"number": "A-1"}}, "extensions": cost(10.0)}).to_string()))
+            .respond_with("GetViewer", TransportResponse::new(StatusCode::OK, json!({"data": {"viewer": {"number": "V-1"}}, "extensions": cost(3.0)}).to_string()));
+
+        let client = Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(transport.clone())
+            .with_rate_limiter(RateLimiter::new())
+            .build().unwrap();
+
+        let mut batch = client.batch();
+        let account = batch.query::<Account, _>("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1")).unwrap();
+        let viewer = batch.query::<Account, _>("GetViewer", "viewer", InputParams::new("id", "ID", "V-1")).unwrap();
+
+        let mut results = batch.send(None).await.unwrap();
+        assert_eq!(results.take(&account).unwrap().number, "A-1");
+        assert_eq!(results.take(&viewer).unwrap().number, "V-1");
+
+        let limiter = client.rate_limiter().unwrap();
+        let costs = limiter.state.lock().unwrap().costs.clone();
+        assert_eq!(costs, HashMap::from([("GetAccount".to_string(), 10.0), ("GetViewer".to_string(), 3.0)]));
+
+        // A batch of both is estimated to cost 13
+        let now = Instant::now();
+        let available = limiter.budget().cost_available_at(now).unwrap();
+        limiter.reserve(&["GetAccount", "GetViewer"], now);
+        assert_eq!(limiter.budget().cost_available_at(now), Some(available - 13.0));
+    }
+}
@@ -0,0 +1,179 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use std::collections::HashSet;
+
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Which headers and JSON fields are masked before requests and responses are logged.
+///
+/// Field names match the whole key or the last component of a prefixed variable name, so `accountNumber`
+/// also masks `account_accountNumber`. Matching ignores case.
+#[derive(Debug, Clone)]
+pub struct Redaction {
+    headers: HashSet<String>,
+    fields: HashSet<String>,
+}
+
+impl Default for Redaction {
+    fn default() -> Self {
+        Redaction::none()
+            .with_header("Authorization")
+            .with_header("Cookie")
+            .with_header("Set-Cookie")
+            .with_header("X-Api-Key")
+            .with_field("accountNumber")
+            .with_field("token")
+            .with_field("refreshToken")
+            .with_field("APIKey")
+            .with_field("password")
+    }
+}
+
+impl Redaction {
+    pub fn new() -> Redaction {
+        Redaction::default()
+    }
+
+    /// Redact nothing, useful as a starting point for a custom list
+    pub fn none() -> Redaction {
+        Redaction {
+            headers: HashSet::new(),
+            fields: HashSet::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str) -> Redaction {
+        self.headers.insert(name.to_lowercase());
+        self
+    }
+
+    pub fn with_field(mut self, name: &str) -> Redaction {
+        self.fields.insert(name.to_lowercase());
+        self
+    }
+
+    pub fn is_redacted_header(&self, name: &str) -> bool {
+        self.headers.contains(&name.to_lowercase())
+    }
+
+    pub fn is_redacted_field(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+
+        self.fields.contains(&name)
+            || name.rsplit_once('_').is_some_and(|(_, last)| self.fields.contains(last))
+    }
+
+    pub fn header_value<'a>(&self, name: &str, value: &'a str) -> &'a str {
+        if self.is_redacted_header(name) {
+            REDACTED
+        }
+        else {
+            value
+        }
+    }
+
+    /// A copy of `value` with every redacted field, at any depth, replaced by `"[REDACTED]"`
+    pub fn json(&self, value: &serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(map) => serde_json::Value::Object(map.iter()
+                .map(|(key, value)| {
+                    if self.is_redacted_field(key) {
+                        (key.clone(), serde_json::Value::String(REDACTED.to_string()))
+                    }
+                    else {
+                        (key.clone(), self.json(value))
+                    }
+                })
+                .collect()),
+            serde_json::Value::Array(list) => serde_json::Value::Array(list.iter().map(|value| self.json(value)).collect()),
+            other => other.clone(),
+        }
+    }
+
+    /// Redact a JSON document held as a string, anything which does not parse is replaced entirely
+    pub fn json_str(&self, json: &str) -> String {
+        match serde_json::from_str::<serde_json::Value>(json) {
+            Ok(value) => self.json(&value).to_string(),
+            Err(_) => format!("{} ({} bytes)", REDACTED, json.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_redact_json() {
+        let redaction = Redaction::new().with_field("postcode");
+
+        let payload = json!({
+            "query": "query Account($account_accountNumber: String!) { ... }",
+            "variables": {
+                "account_accountNumber": "A-B3D8B29D",
+                "account_bills_first": 1,
+                "input": { "APIKey": "sk_live_xyz", "postcode": "SW1A 1AA" }
+            },
+            "data": [ { "token": "jwt", "id": 3 } ]
+        });
+
+        assert_eq!(redaction.json(&payload), json!({
+            "query": "query Account($account_accountNumber: String!) { ... }",
+            "variables": {
+                "account_accountNumber": REDACTED,
+                "account_bills_first": 1,
+                "input": { "APIKey": REDACTED, "postcode": REDACTED }
+            },
+            "data": [ { "token": REDACTED, "id": 3 } ]
+        }));
+    }
+
+    #[test]
+    fn test_redact_none() {
+        let value = json!({ "accountNumber": "A-B3D8B29D" });
+
+        assert_eq!(Redaction::none().json(&value), value);
+        assert_eq!(Redaction::none().header_value("Authorization", "jwt"), "jwt");
+    }
+
+    #[test]
+    fn test_redact_headers() {
+        let redaction = Redaction::new();
+
+        assert_eq!(redaction.header_value("authorization", "jwt"), REDACTED);
+        assert_eq!(redaction.header_value("X-API-KEY", "key"), REDACTED);
+        assert_eq!(redaction.header_value("Content-Type", "application/json"), "application/json");
+    }
+
+    #[test]
+    fn test_redact_json_str() {
+        let redaction = Redaction::new();
+
+        assert_eq!(redaction.json_str(r#"{"token":"jwt"}"#), r#"{"token":"[REDACTED]"}"#);
+        assert_eq!(redaction.json_str("<html>token=jwt</html>"), "[REDACTED] (22 bytes)");
+    }
+}
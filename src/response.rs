@@ -0,0 +1,263 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use reqwest::header::HeaderMap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, GraphQLJsonError, PathSegment};
+
+/// What to do with a response which has errors, after Apollo's `errorPolicy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Fail with the errors and discard any data, the default
+    #[default]
+    Fail,
+    /// Discard the errors and return whatever data there is
+    Ignore,
+    /// Return the data and the errors
+    All,
+}
+
+/// The data for a root field together with any errors, which may have come instead of or as well as it
+#[derive(Debug)]
+pub struct PartialResponse<T> {
+    pub data: Option<T>,
+    pub errors: Vec<GraphQLJsonError>,
+}
+
+impl<T> PartialResponse<T> {
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    /// Apply `error_policy`, failing if it says to or there is no data to return
+    pub fn with_policy(mut self, error_policy: ErrorPolicy) -> Result<PartialResponse<T>, Error> {
+        if self.has_errors() && (error_policy == ErrorPolicy::Fail || self.data.is_none()) {
+            return Err(Error::GraphQLError(self.errors));
+        }
+
+        if error_policy == ErrorPolicy::Ignore {
+            self.errors.clear();
+        }
+
+        Ok(self)
+    }
+
+    /// The data, or the errors if there isn't any
+    pub fn into_result(self) -> Result<T, Error> {
+        match self.data {
+            Some(data) => Ok(data),
+            None if self.has_errors() => Err(Error::GraphQLError(self.errors)),
+            None => Err(Error::InternalError("No response found".to_string())),
+        }
+    }
+}
+
+impl PartialResponse<serde_json::Value> {
+    pub(crate) fn deserialize<T: DeserializeOwned>(self) -> Result<PartialResponse<T>, Error> {
+        Ok(PartialResponse {
+            data: self.data.map(serde_json::from_value).transpose()?,
+            errors: self.errors,
+        })
+    }
+}
+
+/// The whole of a response for one root field, including the top level `extensions` and the HTTP headers
+#[derive(Debug)]
+pub struct ResponseEnvelope<T> {
+    pub data: Option<T>,
+    pub errors: Vec<GraphQLJsonError>,
+    pub extensions: serde_json::Map<String, serde_json::Value>,
+    pub headers: HeaderMap,
+}
+
+impl<T> ResponseEnvelope<T> {
+    /// Decode the extension `name`, if present
+    pub fn extension<E: DeserializeOwned>(&self, name: &str) -> Result<Option<E>, Error> {
+        Ok(self.extensions.get(name).cloned().map(serde_json::from_value).transpose()?)
+    }
+
+    /// Apollo tracing data from the `tracing` extension
+    pub fn tracing(&self) -> Result<Option<Tracing>, Error> {
+        self.extension("tracing")
+    }
+
+    /// Query cost from a `cost` extension in the Shopify style, or a plain numeric `complexity`
+    pub fn cost(&self) -> Result<Option<QueryCost>, Error> {
+        QueryCost::from_extensions(&self.extensions)
+    }
+}
+
+/// The Apollo tracing format, durations are in nanoseconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Tracing {
+    pub version: i32,
+    pub start_time: String,
+    pub end_time: String,
+    pub duration: u64,
+    #[serde(default)]
+    pub execution: Option<TracingExecution>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TracingExecution {
+    pub resolvers: Vec<ResolverTrace>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolverTrace {
+    pub path: Vec<PathSegment>,
+    pub parent_type: String,
+    pub field_name: String,
+    pub return_type: String,
+    pub start_offset: u64,
+    pub duration: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryCost {
+    pub requested_query_cost: Option<f64>,
+    pub actual_query_cost: Option<f64>,
+    pub throttle_status: Option<ThrottleStatus>,
+}
+
+impl QueryCost {
+    pub fn from_extensions(extensions: &serde_json::Map<String, serde_json::Value>) -> Result<Option<QueryCost>, Error> {
+        if let Some(cost) = extensions.get("cost") {
+            return Ok(Some(serde_json::from_value(cost.clone())?));
+        }
+
+        Ok(extensions.get("complexity").and_then(serde_json::Value::as_f64).map(|complexity| QueryCost {
+            actual_query_cost: Some(complexity),
+            ..QueryCost::default()
+        }))
+    }
+}
+
+/// The state of a leaky bucket rate limit on query cost
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ThrottleStatus {
+    pub maximum_available: f64,
+    pub currently_available: f64,
+    pub restore_rate: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::test_server::{StubResponse, StubServer};
+    use crate::{Client, GraphQLType, InputParams};
+
+    fn partial(data: Option<serde_json::Value>) -> PartialResponse<serde_json::Value> {
+        PartialResponse {
+            data,
+            errors: vec![serde_json::from_value(json!({"message": "Not found", "path": ["account", "bills", 0]})).unwrap()],
+        }
+    }
+
+    #[test]
+    fn test_error_policy() {
+        assert!(matches!(partial(Some(json!({}))).with_policy(ErrorPolicy::Fail), Err(Error::GraphQLError(_))));
+
+        let ignored = partial(Some(json!({}))).with_policy(ErrorPolicy::Ignore).unwrap();
+        assert!(!ignored.has_errors());
+
+        let all = partial(Some(json!({}))).with_policy(ErrorPolicy::All).unwrap();
+        assert_eq!(all.errors.len(), 1);
+        assert_eq!(all.data, Some(json!({})));
+
+        assert!(matches!(partial(None).with_policy(ErrorPolicy::Ignore), Err(Error::GraphQLError(_))));
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Account {
+        number: String,
+    }
+
+    impl GraphQLType<InputParams<&str>> for Account {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "number".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_envelope() {
+        let server = StubServer::start(|_request| {
+            StubResponse::new(200, "application/json", &json!({
+                "data": {"account": {"number": "A-1"}},
+                "extensions": {
+                    "cost": {
+                        "requestedQueryCost": 12,
+                        "actualQueryCost": 10,
+                        "throttleStatus": {"maximumAvailable": 1000.0, "currentlyAvailable": 990, "restoreRate": 50.0}
+                    },
+                    "tracing": {
+                        "version": 1,
+                        "startTime": "2024-01-01T00:00:00.000Z",
+                        "endTime": "2024-01-01T00:00:00.002Z",
+                        "duration": 2000000,
+                        "execution": {"resolvers": [
+                            {"path": ["account"], "parentType": "Query", "fieldName": "account", "returnType": "Account", "startOffset": 100, "duration": 1500000}
+                        ]}
+                    }
+                }
+            }).to_string()).with_header("X-Request-Id", "req-1")
+        }).await;
+
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let envelope = client.new_call_envelope::<Account, _>("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1"), None).await.unwrap();
+
+        assert_eq!(envelope.data.as_ref().unwrap().number, "A-1");
+        assert!(envelope.errors.is_empty());
+        assert_eq!(envelope.headers["x-request-id"], "req-1");
+
+        let cost = envelope.cost().unwrap().unwrap();
+        assert_eq!(cost.actual_query_cost, Some(10.0));
+        assert_eq!(cost.throttle_status.unwrap().currently_available, 990.0);
+
+        let tracing = envelope.tracing().unwrap().unwrap();
+        assert_eq!(tracing.duration, 2000000);
+        assert_eq!(tracing.execution.unwrap().resolvers[0].field_name, "account");
+
+        assert_eq!(envelope.extension::<serde_json::Value>("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_complexity() {
+        let extensions = json!({"complexity": 25}).as_object().unwrap().clone();
+
+        assert_eq!(QueryCost::from_extensions(&extensions).unwrap().unwrap().actual_query_cost, Some(25.0));
+        assert_eq!(QueryCost::from_extensions(&serde_json::Map::new()).unwrap(), None);
+    }
+}
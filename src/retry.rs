@@ -0,0 +1,369 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+
+use crate::transport::Received;
+use crate::Error;
+
+/// When and how often to retry requests which fail for transient reasons.
+///
+/// Transport errors (connection failures, resets and timeouts) and the statuses 429, 502, 503 and 504 are
+/// retried, with exponential backoff between attempts unless the server sends a `Retry-After` header.
+/// Mutations are only retried if their request name has been marked idempotent.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: u32,
+    jitter: bool,
+    retryable_statuses: HashSet<StatusCode>,
+    idempotent_mutations: HashSet<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2,
+            jitter: true,
+            retryable_statuses: HashSet::from([
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ]),
+            idempotent_mutations: HashSet::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// The total number of attempts including the first
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> RetryPolicy {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> RetryPolicy {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// The longest delay between attempts, a `Retry-After` longer than this is not waited for and the error is
+    /// returned instead.
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> RetryPolicy {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn with_multiplier(mut self, multiplier: u32) -> RetryPolicy {
+        self.multiplier = multiplier.max(1);
+        self
+    }
+
+    /// Randomise each delay to between half and all of the computed backoff, on by default
+    pub fn with_jitter(mut self, jitter: bool) -> RetryPolicy {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_retryable_status(mut self, status: StatusCode) -> RetryPolicy {
+        self.retryable_statuses.insert(status);
+        self
+    }
+
+    /// Allow the mutation with this request name to be retried
+    pub fn with_idempotent_mutation(mut self, request_name: &str) -> RetryPolicy {
+        self.idempotent_mutations.insert(request_name.to_string());
+        self
+    }
+
+    pub(crate) fn is_idempotent_mutation(&self, request_name: &str) -> bool {
+        self.idempotent_mutations.contains(request_name)
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// The backoff before retry number `retry`, starting at 1, before jitter is applied
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(retry.saturating_sub(1));
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+
+    fn jittered(&self, backoff: Duration) -> Duration {
+        if self.jitter {
+            backoff / 2 + backoff.mul_f64(fastrand::f64() / 2.0)
+        }
+        else {
+            backoff
+        }
+    }
+
+    /// How long to wait before retrying after `attempt` attempts produced `result`, or `None` if it should not be retried
+    pub(crate) fn retry_delay<R: Received>(&self, attempt: u32, result: Result<&R, &Error>) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        match result {
+            Ok(response) if self.retryable_statuses.contains(&response.status()) => {
+                match retry_after(response.headers()) {
+                    Some(delay) if delay > self.max_backoff => None,
+                    Some(delay) => Some(delay),
+                    None => Some(self.jittered(self.backoff(attempt))),
+                }
+            },
+            Err(Error::IOError(error)) if error.is_connect() || error.is_timeout() || error.is_request() || error.is_body() => {
+                Some(self.jittered(self.backoff(attempt)))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Parse a `Retry-After` header given either as a number of seconds or an HTTP date
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = OffsetDateTime::parse(value, &Rfc2822).ok()?;
+    let delay = date - OffsetDateTime::now_utc();
+
+    Some(delay.try_into().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use futures::future::BoxFuture;
+    use reqwest::header::HeaderValue;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::test_server::{StubResponse, StubServer};
+    use crate::transport::{ReqwestTransport, Transport, TransportRequest, TransportResponse};
+    use crate::{Client, GraphQLType, InputParams, NoParams};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Viewer {
+        id: String,
+    }
+
+    impl<Q: crate::GraphQLQueryParams> GraphQLType<Q> for Viewer {
+        fn get_query_attributes(_params: &Q, _prefix: &str) -> String {
+            "id".to_string()
+        }
+    }
+
+    /// Fails the first `failures` requests with `failure`, then succeeds
+    async fn flaky_server(failures: usize, failure: StubResponse) -> StubServer {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        StubServer::start(move |_request| {
+            if count.fetch_add(1, Ordering::SeqCst) < failures {
+                failure.clone()
+            }
+            else {
+                StubResponse::new(200, "application/json", &json!({"data": {"viewer": {"id": "V-1"}}}).to_string())
+            }
+        }).await
+    }
+
+    fn retrying_client(server: &StubServer, policy: RetryPolicy) -> Client {
+        Client::builder().with_url(server.url.clone()).unwrap()
+            .with_retry_policy(policy.with_initial_backoff(Duration::from_millis(1)))
+            .build().unwrap()
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::new()
+            .with_initial_backoff(Duration::from_millis(100))
+            .with_max_backoff(Duration::from_millis(500));
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(500));
+        assert_eq!(policy.backoff(40), Duration::from_millis(500));
+
+        for _ in 0..100 {
+            let delay = policy.jittered(Duration::from_millis(100));
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_success() {
+        let server = flaky_server(2, StubResponse::new(503, "text/plain", "Unavailable")).await;
+        let client = retrying_client(&server, RetryPolicy::new());
+
+        let viewer: Viewer = client.new_call("Viewer", "viewer", NoParams, None).await.unwrap();
+
+        assert_eq!(viewer.id, "V-1");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up() {
+        let server = flaky_server(5, StubResponse::new(502, "text/plain", "Bad Gateway")).await;
+        let client = retrying_client(&server, RetryPolicy::new().with_max_attempts(2));
+
+        let result = client.new_call::<Viewer, NoParams>("Viewer", "viewer", NoParams, None).await;
+
+        assert!(matches!(result, Err(Error::HttpError(StatusCode::BAD_GATEWAY))));
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_honoured() {
+        let server = flaky_server(1, StubResponse::new(429, "text/plain", "Slow down").with_header("Retry-After", "0")).await;
+        let client = retrying_client(&server, RetryPolicy::new());
+
+        let _: Viewer = client.new_call("Viewer", "viewer", NoParams, None).await.unwrap();
+        assert_eq!(server.requests().len(), 2);
+
+        let server = flaky_server(1, StubResponse::new(429, "text/plain", "Slow down").with_header("Retry-After", "3600")).await;
+        let client = retrying_client(&server, RetryPolicy::new());
+
+        let result = client.new_call::<Viewer, NoParams>("Viewer", "viewer", NoParams, None).await;
+        assert!(matches!(result, Err(Error::HttpError(StatusCode::TOO_MANY_REQUESTS))));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_not_retryable_status() {
+        let server = flaky_server(1, StubResponse::new(400, "text/plain", "Bad Request")).await;
+        let client = retrying_client(&server, RetryPolicy::new());
+
+        let result = client.new_call::<Viewer, NoParams>("Viewer", "viewer", NoParams, None).await;
+
+        assert!(matches!(result, Err(Error::HttpError(StatusCode::BAD_REQUEST))));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mutations_not_retried() {
+        let server = flaky_server(1, StubResponse::new(503, "text/plain", "Unavailable")).await;
+        let client = retrying_client(&server, RetryPolicy::new());
+
+        let result = client.new_mutation::<Viewer, _>("Rename", "viewer", InputParams::new("name", "String!", "Bob"), None).await;
+
+        assert!(matches!(result, Err(Error::HttpError(StatusCode::SERVICE_UNAVAILABLE))));
+        assert_eq!(server.requests().len(), 1);
+
+        let server = flaky_server(1, StubResponse::new(503, "text/plain", "Unavailable")).await;
+        let client = retrying_client(&server, RetryPolicy::new());
+
+        let result = client.call("Rename", "\n  mutation Rename { viewer { id } }", &json!({}), None).await;
+
+        assert!(matches!(result, Err(Error::HttpError(StatusCode::SERVICE_UNAVAILABLE))));
+        assert_eq!(server.requests().len(), 1);
+
+        let server = flaky_server(1, StubResponse::new(503, "text/plain", "Unavailable")).await;
+        let client = retrying_client(&server, RetryPolicy::new());
+
+        let result = client.call("Rename", "# Rename the viewer\nfragment Id on Viewer { id }\nmutation Rename { viewer { ...Id } }", &json!({}), None).await;
+
+        assert!(matches!(result, Err(Error::HttpError(StatusCode::SERVICE_UNAVAILABLE))));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_mutation_retried() {
+        let server = flaky_server(1, StubResponse::new(503, "text/plain", "Unavailable")).await;
+        let client = retrying_client(&server, RetryPolicy::new().with_idempotent_mutation("Rename"));
+
+        let viewer = client.new_mutation::<Viewer, _>("Rename", "viewer", InputParams::new("name", "String!", "Bob"), None).await.unwrap();
+
+        assert_eq!(viewer.id, "V-1");
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    /// Sends with `reqwest` and counts the attempts
+    struct CountingTransport {
+        inner: ReqwestTransport,
+        attempts: Arc<AtomicUsize>,
+    }
+
+    impl Transport for CountingTransport {
+        fn send(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportResponse, Error>> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            self.inner.send(request)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_refused_retried() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/graphql", listener.local_addr().unwrap());
+        drop(listener);
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let client = Client::builder().with_url(url).unwrap()
+            .with_transport(CountingTransport { inner: ReqwestTransport::default(), attempts: attempts.clone() })
+            .with_retry_policy(RetryPolicy::new().with_initial_backoff(Duration::from_millis(1)))
+            .build().unwrap();
+
+        let result = client.new_call::<Viewer, NoParams>("Viewer", "viewer", NoParams, None).await;
+
+        assert!(matches!(result, Err(Error::IOError(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
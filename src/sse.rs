@@ -0,0 +1,247 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+//! Subscriptions over Server-Sent Events in the graphql-sse "distinct connections" mode
+//! see https://github.com/enisdenjo/graphql-sse/blob/master/PROTOCOL.md
+
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use tokio::sync::mpsc;
+
+use crate::{Error, GraphQLResponse, Subscription};
+
+pub const CONTENT_TYPE: &str = "text/event-stream";
+
+#[derive(Debug, PartialEq)]
+pub struct SseEvent {
+    pub event: String,
+    pub data: String,
+}
+
+/// Incremental parser for a `text/event-stream` body, chunks may split lines, events and UTF-8 characters
+/// anywhere. Only complete lines are decoded.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buf: Vec<u8>,
+    event: Option<String>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    pub fn new() -> SseParser {
+        SseParser::default()
+    }
+
+    /// Add the next chunk of the body and return any events which are now complete
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buf.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+
+        while let Some(pos) = self.buf.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.is_empty() {
+                if let Some(event) = self.dispatch() {
+                    events.push(event);
+                }
+                continue;
+            }
+
+            if line.starts_with(':') {
+                continue;
+            }
+
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+                None => (line, ""),
+            };
+
+            match field {
+                "event" => self.event = Some(value.to_string()),
+                "data" => self.data.push(value.to_string()),
+                _ => {},
+            }
+        }
+
+        events
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+
+        if event.is_none() && self.data.is_empty() {
+            return None;
+        }
+
+        let data = self.data.join("\n");
+        self.data.clear();
+
+        Some(SseEvent {
+            event: event.unwrap_or_else(|| "message".to_string()),
+            data,
+        })
+    }
+}
+
+pub(crate) fn subscribe<T: DeserializeOwned + Send + 'static>(response: reqwest::Response, query_name: &str) -> Subscription<T> {
+    let (sender, receiver) = mpsc::channel(16);
+
+    tokio::spawn(run(response, sender, query_name.to_string()));
+
+    Subscription::new(receiver)
+}
+
+async fn run<T: DeserializeOwned>(response: reqwest::Response, sender: mpsc::Sender<Result<T, Error>>, query_name: String) {
+    let mut body = response.bytes_stream();
+    let mut parser = SseParser::new();
+
+    loop {
+        let chunk = tokio::select! {
+            chunk = body.next() => chunk,
+            _ = sender.closed() => return,
+        };
+
+        let chunk = match chunk {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(error)) => {
+                let _ = sender.send(Err(error.into())).await;
+                return;
+            },
+            None => return,
+        };
+
+        for event in parser.push(&chunk) {
+            match event.event.as_str() {
+                "next" => {
+                    let result = serde_json::from_str::<GraphQLResponse>(&event.data)
+                        .map_err(Error::from)
+                        .and_then(|response| response.into_result(&query_name));
+
+                    if sender.send(result).await.is_err() {
+                        return;
+                    }
+                },
+                "complete" => return,
+                _ => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use display_json::DisplayAsJsonPretty;
+    use serde::{Deserialize, Serialize};
+
+    use crate::test_server::{StubResponse, StubServer};
+    use crate::{Client, GraphQLType, NoParams};
+
+    #[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
+    #[serde(rename_all = "camelCase")]
+    struct Reading {
+        value: i32,
+    }
+
+    impl GraphQLType<NoParams> for Reading {
+        fn get_query_attributes(_params: &NoParams, _prefix: &str) -> String {
+            "value".to_string()
+        }
+    }
+
+    #[test]
+    fn test_parse_split_chunks() {
+        let mut parser = SseParser::new();
+
+        assert_eq!(parser.push(b": keep alive\n\nevent: ne"), vec![]);
+        assert_eq!(parser.push(b"xt\r\ndata: {\"a\":\ndata: 1}\r\n"), vec![]);
+        assert_eq!(parser.push(b"\r\nevent: complete\n"), vec![SseEvent {
+            event: "next".to_string(),
+            data: "{\"a\":\n1}".to_string(),
+        }]);
+        assert_eq!(parser.push(b"data:\n\n"), vec![SseEvent {
+            event: "complete".to_string(),
+            data: "".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn test_parse_split_codepoint() {
+        let mut parser = SseParser::new();
+        let bytes = "data: {\"meter\":\"Küche\"}\n\n".as_bytes();
+        let split = bytes.iter().position(|byte| *byte == 0xC3).unwrap() + 1;
+
+        assert_eq!(parser.push(&bytes[..split]), vec![]);
+        assert_eq!(parser.push(&bytes[split..]), vec![SseEvent {
+            event: "message".to_string(),
+            data: "{\"meter\":\"Küche\"}".to_string(),
+        }]);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_sse() {
+        let body = concat!(
+            "event: next\ndata: {\"data\":{\"reading\":{\"value\":1}}}\n\n",
+            ":\n\n",
+            "event: next\ndata: {\"data\":{\"reading\":{\"value\":2}}}\n\n",
+            "event: next\ndata: {\"data\":{},\"errors\":[{\"message\":\"Meter offline\",\"locations\":[],\"path\":[\"reading\"],\"extensions\":{}}]}\n\n",
+            "event: complete\ndata:\n\n",
+        );
+        let server = StubServer::start(move |_request| StubResponse::new(200, CONTENT_TYPE, body)).await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        let subscription = client.subscribe_sse::<Reading, NoParams>("Readings", "reading", NoParams, None).await.unwrap();
+        let results: Vec<Result<Reading, Error>> = subscription.collect().await;
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().value, 1);
+        assert_eq!(results[1].as_ref().unwrap().value, 2);
+        match &results[2] {
+            Err(Error::GraphQLError(errors)) => assert_eq!(errors[0].message.as_deref(), Some("Meter offline")),
+            other => panic!("Expected GraphQLError but got {:?}", other),
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].headers["accept"], CONTENT_TYPE);
+        assert_eq!(requests[0].json()["operationName"], "Readings");
+        assert!(requests[0].json()["query"].as_str().unwrap().contains("subscription Readings"));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_sse_http_error() {
+        let server = StubServer::start(|_request| StubResponse::new(401, "text/plain", "Unauthorized")).await;
+        let client = Client::builder().with_url(server.url.clone()).unwrap().build().unwrap();
+
+        match client.subscribe_sse::<Reading, NoParams>("Readings", "reading", NoParams, None).await {
+            Err(Error::HttpError(status)) => assert_eq!(status.as_u16(), 401),
+            Err(other) => panic!("Expected HttpError but got {}", other),
+            Ok(_) => panic!("Expected HttpError"),
+        }
+    }
+}
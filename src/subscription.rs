@@ -0,0 +1,478 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+//! Subscriptions over the `graphql-transport-ws` WebSocket protocol
+//! see https://github.com/enisdenjo/graphql-ws/blob/master/PROTOCOL.md
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::{SinkExt, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::{Error, GraphQLJsonError, GraphQLResponse};
+
+pub const PROTOCOL: &str = "graphql-transport-ws";
+
+const SUBSCRIPTION_ID: &str = "1";
+
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SubscribePayload {
+    pub query:          String,
+    pub variables:      serde_json::Value,
+    pub operation_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ProtocolMessage {
+    ConnectionInit {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<serde_json::Value>
+    },
+    ConnectionAck {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<serde_json::Value>
+    },
+    Ping {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<serde_json::Value>
+    },
+    Pong {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<serde_json::Value>
+    },
+    Subscribe {
+        id: String,
+        payload: SubscribePayload
+    },
+    Next {
+        id: String,
+        payload: GraphQLResponse
+    },
+    Error {
+        id: String,
+        payload: Vec<GraphQLJsonError>
+    },
+    Complete {
+        id: String
+    },
+}
+
+impl ProtocolMessage {
+    fn to_message(&self) -> Result<Message, Error> {
+        Ok(Message::text(serde_json::to_string(self)?))
+    }
+}
+
+/// A stream of typed results from a subscription.
+///
+/// Dropping the stream sends `complete` to the server and closes the socket.
+pub struct Subscription<T> {
+    receiver: mpsc::Receiver<Result<T, Error>>,
+}
+
+impl<T> Subscription<T> {
+    pub(crate) fn new(receiver: mpsc::Receiver<Result<T, Error>>) -> Subscription<T> {
+        Subscription { receiver }
+    }
+}
+
+impl<T> Stream for Subscription<T> {
+    type Item = Result<T, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+/// Derive the WebSocket endpoint from an HTTP endpoint, `https://host/graphql` becomes `wss://host/graphql`
+pub fn ws_url_from(url: &str) -> String {
+    if let Some(rest) = url.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    }
+    else if let Some(rest) = url.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    }
+    else {
+        url.to_string()
+    }
+}
+
+pub(crate) async fn subscribe<T: DeserializeOwned + Send + 'static>(ws_url: &str, payload: SubscribePayload, query_name: &str,
+    connection_params: Option<serde_json::Value>, ack_timeout: Duration, keep_alive: Option<Duration>) -> Result<Subscription<T>, Error> {
+
+    let mut request = ws_url.into_client_request()?;
+    request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(PROTOCOL));
+
+    let (mut socket, _response) = tokio_tungstenite::connect_async(request).await?;
+
+    socket.send(ProtocolMessage::ConnectionInit { payload: connection_params }.to_message()?).await?;
+
+    let acknowledged = async {
+        loop {
+            match next_message(&mut socket).await? {
+                Some(ProtocolMessage::ConnectionAck { .. }) => return Ok(()),
+                Some(ProtocolMessage::Ping { .. }) => {
+                    socket.send(ProtocolMessage::Pong { payload: None }.to_message()?).await?;
+                },
+                Some(other) => return Err(Error::ProtocolError(format!("Expected connection_ack but received {:?}", other))),
+                None => return Err(Error::ProtocolError("Connection closed before connection_ack".to_string())),
+            }
+        }
+    };
+
+    tokio::time::timeout(ack_timeout, acknowledged).await
+        .map_err(|_| Error::ProtocolError(format!("No connection_ack within {:?}", ack_timeout)))??;
+
+    socket.send(ProtocolMessage::Subscribe { id: SUBSCRIPTION_ID.to_string(), payload }.to_message()?).await?;
+
+    let (sender, receiver) = mpsc::channel(16);
+
+    tokio::spawn(run(socket, sender, query_name.to_string(), keep_alive));
+
+    Ok(Subscription::new(receiver))
+}
+
+async fn next_message(socket: &mut Socket) -> Result<Option<ProtocolMessage>, Error> {
+    while let Some(message) = socket.next().await {
+        match message? {
+            Message::Text(text) => return Ok(Some(serde_json::from_str(&text)?)),
+            Message::Close(_) => return Ok(None),
+            _ => {},
+        }
+    }
+    Ok(None)
+}
+
+async fn run<T: DeserializeOwned>(mut socket: Socket, sender: mpsc::Sender<Result<T, Error>>, query_name: String, keep_alive: Option<Duration>) {
+    let mut ticker = keep_alive.map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+    // Set when a ping is sent and cleared by any message from the server
+    let mut awaiting_reply = false;
+
+    loop {
+        let tick = async {
+            match &mut ticker {
+                Some(ticker) => { ticker.tick().await; },
+                None => futures::future::pending::<()>().await,
+            }
+        };
+
+        tokio::select! {
+            message = next_message(&mut socket) => {
+                awaiting_reply = false;
+
+                match message {
+                    Ok(Some(ProtocolMessage::Next { id, payload })) if id == SUBSCRIPTION_ID => {
+                        if sender.send(payload.into_result(&query_name)).await.is_err() {
+                            break;
+                        }
+                    },
+                    Ok(Some(ProtocolMessage::Error { id, payload })) if id == SUBSCRIPTION_ID => {
+                        let _ = sender.send(Err(Error::GraphQLError(payload))).await;
+                        let _ = socket.close(None).await;
+                        return;
+                    },
+                    Ok(Some(ProtocolMessage::Complete { id })) if id == SUBSCRIPTION_ID => {
+                        let _ = socket.close(None).await;
+                        return;
+                    },
+                    Ok(Some(ProtocolMessage::Ping { .. })) => {
+                        if let Err(error) = send(&mut socket, ProtocolMessage::Pong { payload: None }).await {
+                            let _ = sender.send(Err(error)).await;
+                            return;
+                        }
+                    },
+                    Ok(Some(ProtocolMessage::Pong { .. })) => {},
+                    Ok(Some(other)) => {
+                        let _ = sender.send(Err(Error::ProtocolError(format!("Unexpected message {:?}", other)))).await;
+                        let _ = socket.close(None).await;
+                        return;
+                    },
+                    Ok(None) => return,
+                    Err(error) => {
+                        let _ = sender.send(Err(error)).await;
+                        return;
+                    }
+                }
+            },
+            _ = tick => {
+                if awaiting_reply {
+                    let _ = sender.send(Err(Error::ProtocolError("No reply to keep alive ping".to_string()))).await;
+                    let _ = socket.close(None).await;
+                    return;
+                }
+                awaiting_reply = true;
+
+                if let Err(error) = send(&mut socket, ProtocolMessage::Ping { payload: None }).await {
+                    let _ = sender.send(Err(error)).await;
+                    return;
+                }
+            },
+            _ = sender.closed() => break,
+        }
+    }
+
+    // The consumer has gone away, tell the server we are no longer interested
+    let _ = send(&mut socket, ProtocolMessage::Complete { id: SUBSCRIPTION_ID.to_string() }).await;
+    let _ = socket.close(None).await;
+}
+
+async fn send(socket: &mut Socket, message: ProtocolMessage) -> Result<(), Error> {
+    socket.send(message.to_message()?).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use display_json::DisplayAsJsonPretty;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    use crate::{Client, GraphQLType, NoParams};
+
+    #[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
+    #[serde(rename_all = "camelCase")]
+    struct Reading {
+        value: i32,
+    }
+
+    impl GraphQLType<NoParams> for Reading {
+        fn get_query_attributes(_params: &NoParams, _prefix: &str) -> String {
+            "value".to_string()
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    async fn accept(listener: &TcpListener) -> WebSocketStream<TcpStream> {
+        let (stream, _) = listener.accept().await.unwrap();
+
+        tokio_tungstenite::accept_hdr_async(stream, |request: &Request, mut response: Response| {
+            assert_eq!(request.headers().get("Sec-WebSocket-Protocol").unwrap(), PROTOCOL);
+            response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(PROTOCOL));
+            Ok(response)
+        }).await.unwrap()
+    }
+
+    async fn receive(socket: &mut WebSocketStream<TcpStream>) -> serde_json::Value {
+        loop {
+            if let Message::Text(text) = socket.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    async fn listen() -> (TcpListener, Client) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/graphql", listener.local_addr().unwrap());
+        let client = Client::builder().with_url(url).unwrap().build().unwrap();
+
+        (listener, client)
+    }
+
+    #[test]
+    fn test_ws_url_from() {
+        assert_eq!(ws_url_from("https://api.example.com/v1/graphql/"), "wss://api.example.com/v1/graphql/");
+        assert_eq!(ws_url_from("http://localhost:8080/graphql"), "ws://localhost:8080/graphql");
+        assert_eq!(ws_url_from("ws://localhost:8080/graphql"), "ws://localhost:8080/graphql");
+    }
+
+    #[tokio::test]
+    async fn test_subscription() {
+        let (listener, client) = listen().await;
+
+        let server = tokio::spawn(async move {
+            let mut socket = accept(&listener).await;
+
+            let init = receive(&mut socket).await;
+            assert_eq!(init["type"], "connection_init");
+            assert_eq!(init["payload"]["Authorization"], "secret");
+            socket.send(Message::text(r#"{"type":"connection_ack"}"#)).await.unwrap();
+
+            let subscribe = receive(&mut socket).await;
+            assert_eq!(subscribe["type"], "subscribe");
+            assert_eq!(subscribe["payload"]["operationName"], "Readings");
+            assert!(subscribe["payload"]["query"].as_str().unwrap().contains("subscription Readings"));
+            let id = subscribe["id"].as_str().unwrap().to_string();
+
+            socket.send(Message::text(r#"{"type":"ping"}"#)).await.unwrap();
+            assert_eq!(receive(&mut socket).await["type"], "pong");
+
+            for value in [1, 2] {
+                let next = json!({"id": id, "type": "next", "payload": {"data": {"reading": {"value": value}}}});
+                socket.send(Message::text(next.to_string())).await.unwrap();
+            }
+            socket.send(Message::text(json!({"id": id, "type": "complete"}).to_string())).await.unwrap();
+        });
+
+        let subscription = client.subscribe::<Reading, NoParams>("Readings", "reading", NoParams, Some(json!({"Authorization": "secret"}))).await.unwrap();
+        let readings: Vec<Result<Reading, Error>> = subscription.collect().await;
+
+        server.await.unwrap();
+
+        let values: Vec<i32> = readings.into_iter().map(|reading| reading.unwrap().value).collect();
+        assert_eq!(values, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_subscription_error() {
+        let (listener, client) = listen().await;
+
+        let server = tokio::spawn(async move {
+            let mut socket = accept(&listener).await;
+
+            receive(&mut socket).await;
+            socket.send(Message::text(r#"{"type":"connection_ack"}"#)).await.unwrap();
+
+            let id = receive(&mut socket).await["id"].as_str().unwrap().to_string();
+            let error = json!({"id": id, "type": "error", "payload": [{
+                "message": "Not authorized",
+                "locations": [],
+                "path": ["reading"],
+                "extensions": {"errorType": "AUTHORIZATION"}
+            }]});
+            socket.send(Message::text(error.to_string())).await.unwrap();
+        });
+
+        let mut subscription = client.subscribe::<Reading, NoParams>("Readings", "reading", NoParams, None).await.unwrap();
+
+        match subscription.next().await {
+            Some(Err(Error::GraphQLError(errors))) => assert_eq!(errors[0].message.as_deref(), Some("Not authorized")),
+            other => panic!("Expected GraphQLError but got {:?}", other),
+        }
+        assert!(subscription.next().await.is_none());
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_and_drop() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = Client::builder()
+            .with_url(format!("http://{}/graphql", listener.local_addr().unwrap())).unwrap()
+            .with_keep_alive(Duration::from_millis(10))
+            .build().unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut socket = accept(&listener).await;
+
+            receive(&mut socket).await;
+            socket.send(Message::text(r#"{"type":"connection_ack"}"#)).await.unwrap();
+
+            let id = receive(&mut socket).await["id"].as_str().unwrap().to_string();
+            socket.send(Message::text(json!({"id": id, "type": "next", "payload": {"data": {"reading": {"value": 7}}}}).to_string())).await.unwrap();
+
+            let ping = receive(&mut socket).await;
+            assert_eq!(ping["type"], "ping");
+            socket.send(Message::text(r#"{"type":"pong"}"#)).await.unwrap();
+
+            // The client drops the stream after the first value, so the next message we see other than a ping must be complete
+            loop {
+                let message = receive(&mut socket).await;
+                if message["type"] == "ping" {
+                    socket.send(Message::text(r#"{"type":"pong"}"#)).await.unwrap();
+                }
+                else {
+                    assert_eq!(message["type"], "complete");
+                    assert_eq!(message["id"], id.as_str());
+                    break;
+                }
+            }
+        });
+
+        let mut subscription = client.subscribe::<Reading, NoParams>("Readings", "reading", NoParams, None).await.unwrap();
+        assert_eq!(subscription.next().await.unwrap().unwrap().value, 7);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(subscription);
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ack_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = Client::builder()
+            .with_url(format!("http://{}/graphql", listener.local_addr().unwrap())).unwrap()
+            .with_ack_timeout(Duration::from_millis(50))
+            .build().unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut socket = accept(&listener).await;
+            receive(&mut socket).await;
+            // Never acknowledge, hold the socket open until the client gives up
+            while let Some(Ok(_)) = socket.next().await {}
+        });
+
+        match client.subscribe::<Reading, NoParams>("Readings", "reading", NoParams, None).await {
+            Err(Error::ProtocolError(message)) => assert!(message.contains("connection_ack")),
+            Err(other) => panic!("Expected ProtocolError but got {}", other),
+            Ok(_) => panic!("Expected ProtocolError"),
+        }
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = Client::builder()
+            .with_url(format!("http://{}/graphql", listener.local_addr().unwrap())).unwrap()
+            .with_keep_alive(Duration::from_millis(20))
+            .build().unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut socket = accept(&listener).await;
+            receive(&mut socket).await;
+            socket.send(Message::text(r#"{"type":"connection_ack"}"#)).await.unwrap();
+            // Ignore the pings
+            while let Some(Ok(_)) = socket.next().await {}
+        });
+
+        let mut subscription = client.subscribe::<Reading, NoParams>("Readings", "reading", NoParams, None).await.unwrap();
+
+        match subscription.next().await {
+            Some(Err(Error::ProtocolError(message))) => assert!(message.contains("keep alive")),
+            other => panic!("Expected ProtocolError but got {:?}", other),
+        }
+        assert!(subscription.next().await.is_none());
+
+        server.await.unwrap();
+    }
+}
@@ -24,11 +24,18 @@ SOFTWARE.
 
 
 use std::collections::HashMap;
+use std::fmt::{self, Display};
 
 use display_json::DisplayAsJsonPretty;
+use serde::ser::Error as _;
 use serde::{Deserialize, Serialize};
 use serde_json::Error;
 
+use crate::types::upload::{self, Upload};
+
+pub(crate) const UPLOADS_NOT_SUPPORTED: &str = "Uploads can only be sent by new_call or new_mutation";
+
+#[derive(Clone)]
 pub struct ParamBuffer {
     buf: String
 }
@@ -41,7 +48,7 @@ impl ParamBuffer {
     }
 
     pub fn push(&mut self, s: &str) {
-        self.buf.push_str(if self.buf.len() == 0 {
+        self.buf.push_str(if self.buf.is_empty() {
             "("
         }
         else {
@@ -52,7 +59,7 @@ impl ParamBuffer {
     }
 
     pub fn consume(mut self) -> String {
-        if self.buf.len() > 0 {
+        if !self.buf.is_empty() {
             self.buf.push(')');
         }
         self.buf
@@ -74,38 +81,83 @@ impl ParamBuffer {
     }
 }
 
+impl Default for ParamBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct VariableBuffer {
-    map: HashMap<String, serde_json::Value>
+    map: HashMap<String, serde_json::Value>,
+    uploads: Vec<(String, Upload)>,
 }
 
 impl VariableBuffer {
     pub fn new() -> VariableBuffer {
         VariableBuffer {
-            map: HashMap::new()
+            map: HashMap::new(),
+            uploads: Vec::new(),
         }
     }
 
+    /// Any `Upload`s in the value are collected, with their path in the variables, and replaced by nulls
     pub fn push_variable<T: Serialize>(&mut self, prefix: &str, name: &str, value: &T) -> Result<(), Error> {
-       self.map.insert(format!("{}{}", prefix, name), serde_json::to_value(value)?);
+       let name = format!("{}{}", prefix, name);
+       let (mut value, uploads) = upload::collect(value)?;
+
+       if !uploads.is_empty() {
+           upload::extract(&mut value, format!("variables.{}", name), &uploads, &mut self.uploads);
+       }
+
+       self.map.insert(name, value);
        Ok(())
     }
 
+    pub fn uploads(&self) -> &[(String, Upload)] {
+        &self.uploads
+    }
+
+    /// Uploads can only be sent by `new_call` and `new_mutation`, so they are an error here
     pub fn to_string(self) -> Result<String, Error> {
-        serde_json::to_string_pretty(&self.map)
+        serde_json::to_string_pretty(&self.into_map()?)
+    }
+
+    /// The variables, failing if there are uploads since they would be sent as nulls
+    pub(crate) fn into_map(self) -> Result<HashMap<String, serde_json::Value>, Error> {
+        if !self.uploads.is_empty() {
+            return Err(Error::custom(UPLOADS_NOT_SUPPORTED));
+        }
+
+        Ok(self.map)
+    }
+
+    /// Add the variables and uploads of `other`
+    pub(crate) fn append(&mut self, other: VariableBuffer) {
+        self.map.extend(other.map);
+        self.uploads.extend(other.uploads);
+    }
+
+    /// The variables and the uploads found in them
+    pub fn into_parts(self) -> (HashMap<String, serde_json::Value>, Vec<(String, Upload)>) {
+        (self.map, self.uploads)
     }
 }
 
-const EMPTY_STRING: String = String::new();
+impl Default for VariableBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 pub struct GraphQL;
 
 impl GraphQL {
     pub fn prefix(a: &str, b: &str) -> String {
-        if b.len() == 0 {
+        if b.is_empty() {
             a.to_string()
         }
         else {
-            if a.len() == 0 {
+            if a.is_empty() {
                 format!("{}_", b)
             }
             else {
@@ -115,6 +167,23 @@ impl GraphQL {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationType {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+impl Display for OperationType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OperationType::Query => "query",
+            OperationType::Mutation => "mutation",
+            OperationType::Subscription => "subscription",
+        })
+    }
+}
+
 pub trait GraphQLQueryParams {
     fn get_formal_part(&self, params: &mut ParamBuffer, prefix: &str);
     fn get_actual_part(&self, params: &mut ParamBuffer, prefix: &str);
@@ -142,11 +211,19 @@ pub trait GraphQLQueryParams {
         variables.to_string()
     }
 
+    /// Uploads can only be sent by `new_call` and `new_mutation`, so they are an error here
     fn get_variable_map(&self) -> Result<HashMap<String, serde_json::Value>, Error>  {
         let mut variables = VariableBuffer::new();
         self.get_variables_part(&mut variables, "")?;
 
-        Ok(variables.map)
+        variables.into_map()
+    }
+
+    fn get_variable_buffer(&self) -> Result<VariableBuffer, Error> {
+        let mut variables = VariableBuffer::new();
+        self.get_variables_part(&mut variables, "")?;
+
+        Ok(variables)
     }
 
     
@@ -169,7 +246,58 @@ impl GraphQLQueryParams for NoParams {
 }
 
 
+/// Params for an operation which takes a single input object variable, which is the usual shape of a mutation
+/// e.g. `mutation Name($input: TypeName!) { field(input: $input) { ... } }`
+#[derive(Debug)]
+pub struct InputParams<T: Serialize> {
+    name: String,
+    type_name: String,
+    value: T,
+}
+
+impl<T: Serialize> InputParams<T> {
+    pub fn new(name: &str, type_name: &str, value: T) -> InputParams<T> {
+        InputParams {
+            name: name.to_string(),
+            type_name: type_name.to_string(),
+            value,
+        }
+    }
+}
+
+impl<T: Serialize> GraphQLQueryParams for InputParams<T> {
+    fn get_formal_part(&self, params: &mut ParamBuffer, prefix: &str) {
+        params.push_formal(prefix, &self.name, &self.type_name);
+    }
+
+    fn get_actual_part(&self, params: &mut ParamBuffer, prefix: &str) {
+        params.push_actual(prefix, &self.name);
+    }
+
+    fn get_variables_part(&self, variables: &mut VariableBuffer, prefix: &str) -> Result<(), Error> {
+        variables.push_variable(prefix, &self.name, &self.value)
+    }
+}
+
+
 pub trait GraphQLType<Q: GraphQLQueryParams> {
+    /// Build the complete document for an operation whose single root field is `query_name`
+    fn get_document(operation_type: OperationType, request_name: &str, query_name: &str, params: &Q) -> String {
+        format!(r#"
+            {} {}{} {{
+                {}{} {}
+            }}
+        "#, 
+            operation_type,
+            request_name,
+            params.get_formal(),
+            query_name,
+            params.get_actual(""),
+            Self::get_query_part(params, "")
+        )
+    }
+
+
     fn get_query_part(params: &Q, prefix: &str) -> String {
         format!("{{ #get_query_part\n  {}\n}} #/get_query_part\n", Self::get_query_attributes(params, prefix))
     }
@@ -194,4 +322,61 @@ pub trait GraphQLType<Q: GraphQLQueryParams> {
     //         PropertySimpleView::get_query_part()
     // )
     // }
-}
\ No newline at end of file
+}
+
+/// A nullable field selects the same as its type
+impl<T: GraphQLType<Q>, Q: GraphQLQueryParams> GraphQLType<Q> for Option<T> {
+    fn get_query_part(params: &Q, prefix: &str) -> String {
+        T::get_query_part(params, prefix)
+    }
+
+    fn get_query_attributes(params: &Q, prefix: &str) -> String {
+        T::get_query_attributes(params, prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct UpdateInput {
+        account_number: String,
+        nickname: String,
+    }
+
+    struct UpdateResult;
+
+    impl<T: Serialize> GraphQLType<InputParams<T>> for UpdateResult {
+        fn get_query_attributes(_params: &InputParams<T>, _prefix: &str) -> String {
+            "id nickname".to_string()
+        }
+    }
+
+    #[test]
+    fn test_mutation_document() {
+        let params = InputParams::new("input", "UpdateAccountInput!", UpdateInput {
+            account_number: "A-1234".to_string(),
+            nickname: "Home".to_string(),
+        });
+
+        let document = UpdateResult::get_document(OperationType::Mutation, "UpdateNickname", "updateAccount", &params);
+        let document = document.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+        assert!(document.starts_with("mutation UpdateNickname($input: UpdateAccountInput!) { updateAccount(input: $input) {"));
+        assert!(document.contains("id nickname"));
+
+        let variables = params.get_variable_map().unwrap();
+        assert_eq!(variables["input"]["accountNumber"], "A-1234");
+        assert_eq!(variables["input"]["nickname"], "Home");
+    }
+
+    #[test]
+    fn test_query_document() {
+        let document = UpdateResult::get_document(OperationType::Query, "Get", "account", &InputParams::new("accountNumber", "String!", "A-1234"));
+        let document = document.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+        assert!(document.starts_with("query Get($accountNumber: String!) { account(accountNumber: $accountNumber) {"));
+    }
+}
@@ -0,0 +1,424 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use reqwest::header::HeaderMap;
+use reqwest::{Method, StatusCode};
+use serde::Deserialize;
+
+use crate::{multipart, Error};
+
+/// A serialized GraphQL request ready to be sent. A GET request carries the query in the url and has an
+/// empty body and no content type, a POST has a JSON body or a multipart one if it uploads files.
+#[derive(Debug, Clone)]
+pub struct TransportRequest {
+    pub method: Method,
+    pub url: String,
+    pub operation_name: String,
+    pub headers: Vec<(String, String)>,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl TransportRequest {
+    /// The request as the JSON object which would be POSTed, whichever method it uses. For a multipart
+    /// request this is the `operations` part, in which uploads are null.
+    pub fn payload(&self) -> Result<serde_json::Value, Error> {
+        if self.method != Method::GET {
+            let body = match self.content_type.as_deref().filter(|content_type| content_type.starts_with("multipart/form-data")) {
+                Some(content_type) => multipart::operations(content_type, &self.body)
+                    .ok_or_else(|| Error::InternalError("No operations in multipart request".to_string()))?,
+                None => &self.body,
+            };
+
+            return Ok(serde_json::from_slice(body)?);
+        }
+
+        let url = reqwest::Url::parse(&self.url).map_err(|err| Error::InvalidInputError(Box::new(err)))?;
+        let mut payload = serde_json::Map::new();
+
+        for (key, value) in url.query_pairs() {
+            let value = match key.as_ref() {
+                "variables" | "extensions" => serde_json::from_str(&value)?,
+                _ => serde_json::Value::String(value.into_owned()),
+            };
+            payload.insert(key.into_owned(), value);
+        }
+
+        Ok(serde_json::Value::Object(payload))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TransportResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl TransportResponse {
+    pub fn new(status: StatusCode, body: String) -> TransportResponse {
+        TransportResponse {
+            status,
+            headers: HeaderMap::new(),
+            body,
+        }
+    }
+}
+
+/// A response whose body is read as it arrives, for incremental delivery
+pub struct TransportStream {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: BoxStream<'static, Result<Vec<u8>, Error>>,
+}
+
+impl fmt::Debug for TransportStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransportStream")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .finish()
+    }
+}
+
+impl From<TransportResponse> for TransportStream {
+    fn from(response: TransportResponse) -> TransportStream {
+        TransportStream {
+            status: response.status,
+            headers: response.headers,
+            body: futures::stream::once(futures::future::ready(Ok(response.body.into_bytes()))).boxed(),
+        }
+    }
+}
+
+/// Sends requests for a `Client`, the default is `ReqwestTransport`
+pub trait Transport: Send + Sync {
+    fn send(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportResponse, Error>>;
+
+    /// Send a request whose response body is read as it arrives. The default waits for the whole body.
+    fn send_stream(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportStream, Error>> {
+        Box::pin(async move {
+            self.send(request).await.map(TransportStream::from)
+        })
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn send(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportResponse, Error>> {
+        self.as_ref().send(request)
+    }
+
+    fn send_stream(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportStream, Error>> {
+        self.as_ref().send_stream(request)
+    }
+}
+
+/// What retrying and rate limiting need from a response, whether or not its body has been read
+pub(crate) trait Received: Sized + Send {
+    fn status(&self) -> StatusCode;
+    fn headers(&self) -> &HeaderMap;
+    fn send(transport: &dyn Transport, request: TransportRequest) -> BoxFuture<'_, Result<Self, Error>>;
+}
+
+impl Received for TransportResponse {
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn send(transport: &dyn Transport, request: TransportRequest) -> BoxFuture<'_, Result<Self, Error>> {
+        transport.send(request)
+    }
+}
+
+impl Received for TransportStream {
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn send(transport: &dyn Transport, request: TransportRequest) -> BoxFuture<'_, Result<Self, Error>> {
+        transport.send_stream(request)
+    }
+}
+
+/// Sends requests with `reqwest`
+#[derive(Debug, Clone, Default)]
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    pub fn new(client: reqwest::Client) -> ReqwestTransport {
+        ReqwestTransport {
+            client
+        }
+    }
+
+    async fn execute(&self, request: TransportRequest) -> Result<reqwest::Response, Error> {
+        let mut builder = self.client.request(request.method, &request.url);
+
+        for (key, value) in &request.headers {
+            builder = builder.header(key, value);
+        }
+
+        Ok(builder
+            .body(request.body)
+            .send()
+            .await?)
+    }
+}
+
+impl Transport for ReqwestTransport {
+    fn send(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportResponse, Error>> {
+        Box::pin(async move {
+            let response = self.execute(request).await?;
+
+            Ok(TransportResponse {
+                status: response.status(),
+                headers: response.headers().clone(),
+                body: response.text().await?,
+            })
+        })
+    }
+
+    fn send_stream(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportStream, Error>> {
+        Box::pin(async move {
+            let response = self.execute(request).await?;
+
+            Ok(TransportStream {
+                status: response.status(),
+                headers: response.headers().clone(),
+                body: response.bytes_stream()
+                    .map(|chunk| chunk.map(|chunk| chunk.to_vec()).map_err(Error::from))
+                    .boxed(),
+            })
+        })
+    }
+}
+
+/// A request received by a `MockTransport`
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub operation_name: String,
+    pub query: String,
+    pub variables: serde_json::Value,
+    pub headers: Vec<(String, String)>,
+}
+
+/// The parts of a serialized request body which identify it
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RequestBody {
+    #[serde(default)]
+    pub(crate) query: String,
+    #[serde(default)]
+    pub(crate) variables: serde_json::Value,
+    #[serde(default)]
+    pub(crate) operation_name: Option<String>,
+}
+
+/// An in-memory transport for tests which answers each request with responses scripted per operation name
+/// and records what was sent.
+///
+/// Responses for an operation are returned in the order they were added, the last one is repeated once
+/// the others are used up. A request for an operation with nothing scripted fails with `InternalError`.
+///
+/// Each operation in a batch is answered from its own script and recorded as a separate request, a
+/// scripted response which is not 200 OK fails the whole batch.
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    responses: Mutex<HashMap<String, VecDeque<TransportResponse>>>,
+    requests: Mutex<Vec<MockRequest>>,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// Respond to `operation_name` with `{"data": data}`
+    pub fn respond(&self, operation_name: &str, data: serde_json::Value) -> &MockTransport {
+        self.respond_with(operation_name, TransportResponse::new(StatusCode::OK, serde_json::json!({ "data": data }).to_string()))
+    }
+
+    pub fn respond_with(&self, operation_name: &str, response: TransportResponse) -> &MockTransport {
+        self.responses.lock().unwrap()
+            .entry(operation_name.to_string())
+            .or_default()
+            .push_back(response);
+        self
+    }
+
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn next_response(&self, operation_name: &str) -> Option<TransportResponse> {
+        let mut responses = self.responses.lock().unwrap();
+        let queue = responses.get_mut(operation_name)?;
+
+        if queue.len() > 1 {
+            queue.pop_front()
+        }
+        else {
+            queue.front().cloned()
+        }
+    }
+
+    fn answer(&self, operation_name: &str, body: RequestBody, headers: &[(String, String)]) -> Result<TransportResponse, Error> {
+        self.requests.lock().unwrap().push(MockRequest {
+            operation_name: operation_name.to_string(),
+            query: body.query,
+            variables: body.variables,
+            headers: headers.to_vec(),
+        });
+
+        self.next_response(operation_name)
+            .ok_or_else(|| Error::InternalError(format!("No mock response for operation {}", operation_name)))
+    }
+
+    fn answer_batch(&self, operations: Vec<serde_json::Value>, headers: &[(String, String)]) -> Result<TransportResponse, Error> {
+        let mut responses = Vec::with_capacity(operations.len());
+
+        for operation in operations {
+            let body: RequestBody = serde_json::from_value(operation)?;
+            let operation_name = body.operation_name.clone().unwrap_or_default();
+            let response = self.answer(&operation_name, body, headers)?;
+
+            if response.status != StatusCode::OK {
+                return Ok(response);
+            }
+            responses.push(serde_json::from_str::<serde_json::Value>(&response.body)?);
+        }
+
+        Ok(TransportResponse::new(StatusCode::OK, serde_json::Value::Array(responses).to_string()))
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportResponse, Error>> {
+        Box::pin(async move {
+            match request.payload()? {
+                serde_json::Value::Array(operations) => self.answer_batch(operations, &request.headers),
+                payload => self.answer(&request.operation_name, serde_json::from_value(payload)?, &request.headers),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Serialize;
+    use serde_json::json;
+
+    use crate::{Client, GraphQLType, InputParams};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct Account {
+        number: String,
+        balance: i32,
+    }
+
+    impl GraphQLType<InputParams<&str>> for Account {
+        fn get_query_attributes(_params: &InputParams<&str>, _prefix: &str) -> String {
+            "number\n  balance".to_string()
+        }
+    }
+
+    fn mock_client(transport: &Arc<MockTransport>) -> Client {
+        Client::builder().with_url("http://localhost/graphql".to_string()).unwrap()
+            .with_transport(transport.clone())
+            .build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mock_transport() {
+        let transport = Arc::new(MockTransport::new());
+        transport
+            .respond("GetAccount", json!({"account": {"number": "A-1", "balance": 100}}))
+            .respond("GetAccount", json!({"account": {"number": "A-1", "balance": 50}}));
+
+        let client = mock_client(&transport);
+        let params = || InputParams::new("accountNumber", "String!", "A-1");
+
+        let first: Account = client.new_call("GetAccount", "account", params(), None).await.unwrap();
+        let second: Account = client.new_call("GetAccount", "account", params(), None).await.unwrap();
+        let third: Account = client.new_call("GetAccount", "account", params(), None).await.unwrap();
+
+        assert_eq!((first.balance, second.balance, third.balance), (100, 50, 50));
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].operation_name, "GetAccount");
+        assert!(requests[0].query.contains("account(accountNumber: $accountNumber)"));
+        assert_eq!(requests[0].variables, json!({"accountNumber": "A-1"}));
+        assert!(requests[0].headers.contains(&("Content-Type".to_string(), "application/json".to_string())));
+        assert_eq!(first.number, "A-1");
+    }
+
+    #[tokio::test]
+    async fn test_mock_transport_unscripted() {
+        let transport = Arc::new(MockTransport::new());
+        let client = mock_client(&transport);
+
+        let result = client.new_call::<Account, _>("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1"), None).await;
+
+        assert!(matches!(result, Err(Error::InternalError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_mock_transport_errors() {
+        let transport = Arc::new(MockTransport::new());
+        transport.respond_with("GetAccount", TransportResponse::new(StatusCode::OK, json!({
+            "data": {},
+            "errors": [{"message": "Not found", "locations": [], "path": ["account"], "extensions": {}}]
+        }).to_string()));
+
+        let client = mock_client(&transport);
+
+        let result = client.new_call::<Account, _>("GetAccount", "account", InputParams::new("accountNumber", "String!", "A-1"), None).await;
+
+        match result {
+            Err(Error::GraphQLError(errors)) => assert_eq!(errors[0].message.as_deref(), Some("Not found")),
+            other => panic!("Expected GraphQLError but got {:?}", other),
+        }
+    }
+}
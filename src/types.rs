@@ -40,5 +40,8 @@ pub use int::Int;
 pub mod float;
 pub use float::Float;
 
+pub mod upload;
+pub use upload::Upload;
+
 pub mod page_info;
-pub use page_info::{ForwardPageInfo, ForwardPageOf};
\ No newline at end of file
+pub use page_info::{PageInfo, ForwardPageInfo, ForwardPageOf, BackwardPageInfo, BackwardPageOf, Connection, Edge, EdgeOf, NoEdgeFields};
\ No newline at end of file
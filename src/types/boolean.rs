@@ -110,6 +110,7 @@ impl Display for Boolean {
   }
   
   #[cfg(test)]
+  #[allow(clippy::bool_assert_comparison, clippy::redundant_pattern_matching)]
   mod tests {
       use display_json::DisplayAsJsonPretty;
     use serde::Deserialize;
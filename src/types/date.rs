@@ -82,6 +82,7 @@ impl PartialEq for Date {
     }
 }
 
+#[allow(clippy::non_canonical_partial_ord_impl)]
 impl PartialOrd for Date {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         self.0.partial_cmp(&other.0)
@@ -159,6 +160,7 @@ impl Display for Date {
   
   
   #[cfg(test)]
+  #[allow(clippy::redundant_pattern_matching)]
   mod tests {
       use display_json::DisplayAsJsonPretty;
     use serde::Deserialize;
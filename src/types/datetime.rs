@@ -109,6 +109,7 @@ impl PartialEq for DateTime {
     }
 }
 
+#[allow(clippy::non_canonical_partial_ord_impl)]
 impl PartialOrd for DateTime {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         self.0.partial_cmp(&other.0)
@@ -186,6 +187,7 @@ impl Display for DateTime {
   
   
   #[cfg(test)]
+  #[allow(clippy::redundant_pattern_matching)]
   mod tests {
       use display_json::DisplayAsJsonPretty;
     use serde::Deserialize;
@@ -103,6 +103,7 @@ impl Display for Float {
   }
   
   #[cfg(test)]
+  #[allow(clippy::approx_constant, clippy::redundant_pattern_matching)]
   mod tests {
       use display_json::DisplayAsJsonPretty;
     use serde::Deserialize;
@@ -107,6 +107,7 @@ impl Display for ID {
   }
   
   #[cfg(test)]
+  #[allow(clippy::redundant_pattern_matching)]
   mod tests {
     use display_json::DisplayAsJsonPretty;
     use serde::Deserialize;
@@ -175,6 +175,7 @@ impl Display for Int {
   }
   
   #[cfg(test)]
+  #[allow(clippy::redundant_pattern_matching)]
   mod tests {
       use display_json::DisplayAsJsonPretty;
     use serde::Deserialize;
@@ -25,13 +25,53 @@ SOFTWARE.
 use display_json::DisplayAsJsonPretty;
 use serde::{Deserialize, Serialize};
 
+use crate::{GraphQLQueryParams, GraphQLType};
+
+/// The full Relay `PageInfo`, cursors are null when the page is empty
+#[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
+#[serde(rename_all = "camelCase")]
+pub struct PageInfo {
+    pub start_cursor: Option<String>,
+    pub end_cursor: Option<String>,
+    pub has_next_page: bool,
+    pub has_previous_page: bool,
+}
+
+impl PageInfo {
+    pub fn get_query_part() -> String {
+        "pageInfo { startCursor endCursor hasNextPage hasPreviousPage }".to_string()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
 #[serde(rename_all = "camelCase")]
 pub struct ForwardPageInfo {
-    pub start_cursor: String,
+    pub start_cursor: Option<String>,
+    #[serde(default)]
+    pub end_cursor: Option<String>,
     pub has_next_page: bool
 }
 
+impl ForwardPageInfo {
+    pub fn get_query_part() -> String {
+        "pageInfo { startCursor endCursor hasNextPage }".to_string()
+    }
+}
+
+/// Page info for paging backwards with `last` and `before`
+#[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
+#[serde(rename_all = "camelCase")]
+pub struct BackwardPageInfo {
+    pub start_cursor: Option<String>,
+    pub has_previous_page: bool
+}
+
+impl BackwardPageInfo {
+    pub fn get_query_part() -> String {
+        "pageInfo { startCursor hasPreviousPage }".to_string()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ForwardPageOf<T> 
@@ -40,6 +80,85 @@ pub struct ForwardPageOf<T>
     pub edges: Vec<EdgeOf<T>>
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BackwardPageOf<T> 
+{
+    pub page_info: BackwardPageInfo,
+    pub edges: Vec<EdgeOf<T>>
+}
+
+/// A connection which can be paged in either direction.
+///
+/// `E` holds any fields on each edge other than `cursor` and `node`, the default `NoEdgeFields` selects none.
+/// `totalCount` is not part of the Relay spec so it is only requested by `get_query_attributes_with_total_count`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Connection<T, E = NoEdgeFields> 
+{
+    pub page_info: PageInfo,
+    pub edges: Vec<Edge<T, E>>,
+    #[serde(default)]
+    pub total_count: Option<i32>,
+}
+
+impl<T, E> Connection<T, E> {
+    pub fn edges(&self) -> &[Edge<T, E>] {
+        &self.edges
+    }
+
+    pub fn into_edges(self) -> Vec<Edge<T, E>> {
+        self.edges
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &T> {
+        self.edges.iter().map(|edge| &edge.node)
+    }
+
+    pub fn into_nodes(self) -> Vec<T> {
+        self.edges.into_iter().map(|edge| edge.node).collect()
+    }
+
+    pub fn get_query_attributes_with_total_count<Q: GraphQLQueryParams>(params: &Q, prefix: &str) -> String
+    where Self: GraphQLType<Q>
+    {
+        format!("totalCount\n  {}", Self::get_query_attributes(params, prefix))
+    }
+}
+
+impl<T, E> IntoIterator for Connection<T, E> {
+    type Item = T;
+    type IntoIter = std::iter::Map<std::vec::IntoIter<Edge<T, E>>, fn(Edge<T, E>) -> T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.edges.into_iter().map(|edge| edge.node)
+    }
+}
+
+impl<'a, T, E> IntoIterator for &'a Connection<T, E> {
+    type Item = &'a T;
+    type IntoIter = std::iter::Map<std::slice::Iter<'a, Edge<T, E>>, fn(&'a Edge<T, E>) -> &'a T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.edges.iter().map(|edge| &edge.node)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Edge<T, E = NoEdgeFields>
+{
+    #[serde(default)]
+    pub cursor: Option<String>,
+    pub node: T,
+    #[serde(flatten)]
+    pub fields: E,
+}
+
+/// The default edge fields of a `Connection`, which selects nothing beyond `cursor` and `node`
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NoEdgeFields {}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct  EdgeOf<T>
@@ -47,7 +166,62 @@ pub struct  EdgeOf<T>
   pub node: T
 }
 
+macro_rules! impl_nodes {
+    ($page:ident) => {
+        impl<T> $page<T> {
+            pub fn nodes(&self) -> impl Iterator<Item = &T> {
+                self.edges.iter().map(|edge| &edge.node)
+            }
+
+            pub fn into_nodes(self) -> Vec<T> {
+                self.edges.into_iter().map(|edge| edge.node).collect()
+            }
+        }
+
+        impl<T> IntoIterator for $page<T> {
+            type Item = T;
+            type IntoIter = std::iter::Map<std::vec::IntoIter<EdgeOf<T>>, fn(EdgeOf<T>) -> T>;
+
+            fn into_iter(self) -> Self::IntoIter {
+                self.edges.into_iter().map(|edge| edge.node)
+            }
+        }
+    };
+}
+
+impl_nodes!(ForwardPageOf);
+impl_nodes!(BackwardPageOf);
+
+impl<Q: GraphQLQueryParams> GraphQLType<Q> for NoEdgeFields {
+    fn get_query_attributes(_params: &Q, _prefix: &str) -> String {
+        String::new()
+    }
+}
+
+fn get_connection_attributes<T: GraphQLType<Q>, Q: GraphQLQueryParams>(page_info: String, edge_fields: String, params: &Q, prefix: &str) -> String {
+    format!("{}\n  edges {{\n    {}\n    node {}\n  }}", page_info, edge_fields, T::get_query_part(params, prefix))
+}
+
+impl<T: GraphQLType<Q>, Q: GraphQLQueryParams> GraphQLType<Q> for ForwardPageOf<T> {
+    fn get_query_attributes(params: &Q, prefix: &str) -> String {
+        get_connection_attributes::<T, Q>(ForwardPageInfo::get_query_part(), String::new(), params, prefix)
+    }
+}
+
+impl<T: GraphQLType<Q>, Q: GraphQLQueryParams> GraphQLType<Q> for BackwardPageOf<T> {
+    fn get_query_attributes(params: &Q, prefix: &str) -> String {
+        get_connection_attributes::<T, Q>(BackwardPageInfo::get_query_part(), String::new(), params, prefix)
+    }
+}
+
+impl<T: GraphQLType<Q>, E: GraphQLType<Q>, Q: GraphQLQueryParams> GraphQLType<Q> for Connection<T, E> {
+    fn get_query_attributes(params: &Q, prefix: &str) -> String {
+        get_connection_attributes::<T, Q>(PageInfo::get_query_part(), format!("cursor {}", E::get_query_attributes(params, prefix)), params, prefix)
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::useless_conversion, clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -63,7 +237,165 @@ mod tests {
         let value = serde_json::from_str(json).unwrap();
         let forward_page_info = ForwardPageInfo::from(value);
 
-        assert_eq!(forward_page_info.start_cursor, "YXJyYXljb25uZWN0aW9uOjA=");
+        assert_eq!(forward_page_info.start_cursor.as_deref(), Some("YXJyYXljb25uZWN0aW9uOjA="));
         assert_eq!(forward_page_info.has_next_page, true);
+        assert_eq!(forward_page_info.end_cursor, None);
+    }
+
+    #[test]
+    fn test_parse_forward_end_cursor() {
+        let json = r#"
+{
+  "startCursor": "YXJyYXljb25uZWN0aW9uOjA=",
+  "endCursor": "YXJyYXljb25uZWN0aW9uOjk=",
+  "hasNextPage": false
+}
+        "#;
+
+        let forward_page_info: ForwardPageInfo = serde_json::from_str(json).unwrap();
+
+        assert_eq!(forward_page_info.end_cursor.as_deref(), Some("YXJyYXljb25uZWN0aW9uOjk="));
+        assert!(!forward_page_info.has_next_page);
+    }
+
+    #[test]
+    fn test_parse_page_info() {
+        let json = r#"
+{
+  "startCursor": null,
+  "endCursor": null,
+  "hasNextPage": false,
+  "hasPreviousPage": true
+}
+        "#;
+
+        let page_info: PageInfo = serde_json::from_str(json).unwrap();
+
+        assert_eq!(page_info.start_cursor, None);
+        assert_eq!(page_info.end_cursor, None);
+        assert!(!page_info.has_next_page);
+        assert!(page_info.has_previous_page);
+    }
+
+    #[test]
+    fn test_parse_backward_page() {
+        let json = r#"
+{
+  "pageInfo": {
+    "startCursor": "YXJyYXljb25uZWN0aW9uOjU=",
+    "hasPreviousPage": true
+  },
+  "edges": [
+    { "node": 5 },
+    { "node": 6 }
+  ]
+}
+        "#;
+
+        let page: BackwardPageOf<i32> = serde_json::from_str(json).unwrap();
+
+        assert_eq!(page.page_info.start_cursor.as_deref(), Some("YXJyYXljb25uZWN0aW9uOjU="));
+        assert!(page.page_info.has_previous_page);
+        assert_eq!(page.edges.iter().map(|edge| edge.node).collect::<Vec<i32>>(), vec![5, 6]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_connection_query_part() {
+        struct Bill;
+
+        impl GraphQLType<crate::NoParams> for Bill {
+            fn get_query_attributes(_params: &crate::NoParams, _prefix: &str) -> String {
+                "id".to_string()
+            }
+        }
+
+        let query = Connection::<Bill>::get_query_attributes(&crate::NoParams, "");
+        let query = query.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+        assert!(query.starts_with("pageInfo { startCursor endCursor hasNextPage hasPreviousPage } edges { cursor node {"));
+        assert!(query.contains(" id "));
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct Relationship {
+        is_primary: bool,
+    }
+
+    impl GraphQLType<crate::NoParams> for Relationship {
+        fn get_query_attributes(_params: &crate::NoParams, _prefix: &str) -> String {
+            "isPrimary".to_string()
+        }
+    }
+
+    #[test]
+    fn test_parse_connection() {
+        let json = r#"
+{
+  "pageInfo": {
+    "startCursor": "c1",
+    "endCursor": "c2",
+    "hasNextPage": true,
+    "hasPreviousPage": false
+  },
+  "totalCount": 7,
+  "edges": [
+    { "cursor": "c1", "isPrimary": true, "node": "A-1" },
+    { "cursor": "c2", "isPrimary": false, "node": "A-2" }
+  ]
+}
+        "#;
+
+        let connection: Connection<String, Relationship> = serde_json::from_str(json).unwrap();
+
+        assert_eq!(connection.total_count, Some(7));
+        assert_eq!(connection.edges[1].cursor.as_deref(), Some("c2"));
+        assert!(connection.edges[0].fields.is_primary);
+        assert_eq!(connection.nodes().collect::<Vec<&String>>(), vec!["A-1", "A-2"]);
+
+        let primary: Vec<&String> = connection.edges().iter()
+            .filter(|edge| edge.fields.is_primary)
+            .map(|edge| &edge.node)
+            .collect();
+        assert_eq!(primary, vec!["A-1"]);
+        assert_eq!(connection.into_iter().collect::<Vec<String>>(), vec!["A-1", "A-2"]);
+    }
+
+    #[test]
+    fn test_parse_connection_defaults() {
+        let json = r#"
+{
+  "pageInfo": { "startCursor": null, "endCursor": null, "hasNextPage": false, "hasPreviousPage": false },
+  "edges": [ { "node": 1 } ]
+}
+        "#;
+
+        let connection: Connection<i32> = serde_json::from_str(json).unwrap();
+
+        assert_eq!(connection.total_count, None);
+        assert_eq!(connection.edges[0].cursor, None);
+        assert_eq!(connection.into_nodes(), vec![1]);
+    }
+
+    #[test]
+    fn test_page_iterators() {
+        let page: ForwardPageOf<i32> = serde_json::from_str(r#"
+{
+  "pageInfo": { "startCursor": "c1", "hasNextPage": false },
+  "edges": [ { "node": 1 }, { "node": 2 } ]
+}
+        "#).unwrap();
+
+        assert_eq!(page.nodes().copied().collect::<Vec<i32>>(), vec![1, 2]);
+        assert_eq!(page.into_iter().collect::<Vec<i32>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_connection_edge_fields_query_part() {
+        let query = Connection::<Relationship, Relationship>::get_query_attributes_with_total_count(&crate::NoParams, "");
+        let query = query.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+        assert!(query.starts_with("totalCount pageInfo {"));
+        assert!(query.contains("edges { cursor isPrimary node {"));
+    }
+}
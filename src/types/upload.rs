@@ -0,0 +1,194 @@
+/*****************************************************************************
+MIT License
+
+Copyright (c) 2024 Bruce Skingle
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+******************************************************************************/
+
+use std::cell::RefCell;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::ser::{Error as _, SerializeMap};
+use serde::{Serialize, Serializer};
+
+use crate::Error;
+
+/// The key of the placeholder an `Upload` serializes to while `VariableBuffer` is collecting them
+pub(crate) const PLACEHOLDER_KEY: &str = "$sparko_graphql_upload";
+
+thread_local! {
+    static COLLECTED: RefCell<Option<Vec<Upload>>> = const { RefCell::new(None) };
+}
+
+/// A file for the GraphQL `Upload` scalar, sent as a part of a multipart request by `new_call` or
+/// `new_mutation`. It is `null` in the variables, with the multipart `map` saying which file goes where,
+/// and serializing it anywhere else is an error.
+#[derive(Clone)]
+pub struct Upload {
+    file_name: String,
+    content_type: String,
+    content: Arc<[u8]>,
+}
+
+impl Upload {
+    pub fn new(file_name: &str, content_type: &str, content: impl Into<Vec<u8>>) -> Upload {
+        Upload {
+            file_name: file_name.to_string(),
+            content_type: content_type.to_string(),
+            content: content.into().into(),
+        }
+    }
+
+    /// Read the file at `path`, named by its last component
+    pub fn from_path<P: AsRef<Path>>(path: P, content_type: &str) -> Result<Upload, Error> {
+        let path = path.as_ref();
+        let file_name = path.file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        Ok(Upload::new(&file_name, content_type, std::fs::read(path)?))
+    }
+
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+}
+
+impl fmt::Debug for Upload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upload")
+            .field("file_name", &self.file_name)
+            .field("content_type", &self.content_type)
+            .field("len", &self.content.len())
+            .finish()
+    }
+}
+
+impl Serialize for Upload {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let index = COLLECTED.with(|collected| {
+            collected.borrow_mut().as_mut().map(|uploads| {
+                uploads.push(self.clone());
+                uploads.len() - 1
+            })
+        });
+
+        match index {
+            Some(index) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(PLACEHOLDER_KEY, &index)?;
+                map.end()
+            },
+            None => Err(S::Error::custom("Upload can only be sent as a variable of new_call or new_mutation")),
+        }
+    }
+}
+
+/// Serialize `value` with any `Upload`s in it replaced by placeholders, returning the uploads in
+/// placeholder index order
+pub(crate) fn collect<T: Serialize + ?Sized>(value: &T) -> Result<(serde_json::Value, Vec<Upload>), serde_json::Error> {
+    let previous = COLLECTED.with(|collected| collected.replace(Some(Vec::new())));
+    let result = serde_json::to_value(value);
+    let uploads = COLLECTED.with(|collected| collected.replace(previous)).unwrap_or_default();
+
+    Ok((result?, uploads))
+}
+
+/// Replace the placeholders in `value` with nulls, returning each upload with its object path in the form
+/// used by the multipart `map`, e.g. `variables.input.files.0`
+pub(crate) fn extract(value: &mut serde_json::Value, path: String, uploads: &[Upload], found: &mut Vec<(String, Upload)>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            let upload = map.get(PLACEHOLDER_KEY)
+                .and_then(serde_json::Value::as_u64)
+                .filter(|_| map.len() == 1)
+                .and_then(|index| uploads.get(index as usize));
+
+            if let Some(upload) = upload {
+                found.push((path, upload.clone()));
+                *value = serde_json::Value::Null;
+                return;
+            }
+
+            for (key, child) in map.iter_mut() {
+                extract(child, format!("{}.{}", path, key), uploads, found);
+            }
+        },
+        serde_json::Value::Array(items) => {
+            for (index, child) in items.iter_mut().enumerate() {
+                extract(child, format!("{}.{}", path, index), uploads, found);
+            }
+        },
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_serialize() {
+        let upload = Upload::new("readings.csv", "text/csv", "a,b\n1,2\n");
+
+        assert!(serde_json::to_value(&upload).is_err());
+
+        let (value, uploads) = collect(&vec![upload.clone(), upload]).unwrap();
+        assert_eq!(value, json!([{PLACEHOLDER_KEY: 0}, {PLACEHOLDER_KEY: 1}]));
+        assert_eq!(uploads.len(), 2);
+        assert_eq!(uploads[1].content(), b"a,b\n1,2\n");
+    }
+
+    #[test]
+    fn test_extract() {
+        let (mut value, uploads) = collect(&json!({"meter": "M-1"})).unwrap();
+        let mut found = Vec::new();
+        extract(&mut value, "variables.input".to_string(), &uploads, &mut found);
+        assert!(found.is_empty());
+
+        let mut value = json!({"photos": [{PLACEHOLDER_KEY: 0}, {PLACEHOLDER_KEY: 1}]});
+        let uploads = vec![Upload::new("a.jpg", "image/jpeg", vec![1]), Upload::new("b.jpg", "image/jpeg", vec![2])];
+        extract(&mut value, "variables.input".to_string(), &uploads, &mut found);
+
+        assert_eq!(value, json!({"photos": [null, null]}));
+        assert_eq!(found[0].0, "variables.input.photos.0");
+        assert_eq!(found[1].0, "variables.input.photos.1");
+        assert_eq!(found[1].1.file_name(), "b.jpg");
+
+        let mut value = json!({"photo": {PLACEHOLDER_KEY: 2}});
+        let mut found = Vec::new();
+        extract(&mut value, "variables.input".to_string(), &uploads, &mut found);
+
+        assert_eq!(value, json!({"photo": {PLACEHOLDER_KEY: 2}}));
+        assert!(found.is_empty());
+    }
+}